@@ -0,0 +1,338 @@
+use crate::inotify::*;
+use std::{io, path::{Path, PathBuf}, time::SystemTime};
+use serde::{Deserialize, Serialize};
+#[cfg(target_os = "linux")]
+use std::{collections::HashMap, time::{Duration, Instant}};
+#[cfg(target_os = "linux")]
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+#[cfg(target_os = "linux")]
+use simplicio::*;
+
+/// Identifies one watch registered with a backend
+pub type WatchId = i32;
+
+#[derive(Debug)]
+pub enum BackendError {
+    /// No backend exists for this platform yet
+    Unsupported,
+    OSError(io::Error),
+    Utf8Error(std::str::Utf8Error),
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BackendError::Unsupported => write!(f, "File watching is not supported on this platform"),
+            BackendError::OSError(e) => write!(f, "{}", e),
+            BackendError::Utf8Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BackendError::Unsupported => None,
+            BackendError::OSError(e) => Some(e),
+            BackendError::Utf8Error(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<INotifyError> for BackendError {
+    fn from(error: INotifyError) -> Self {
+        match error {
+            INotifyError::OSError(e) | INotifyError::IOError(e) => BackendError::OSError(e),
+            INotifyError::Utf8Error(e) => BackendError::Utf8Error(e),
+        }
+    }
+}
+
+/// One filesystem event reported by a backend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FsEvent {
+    /// The watch that reported it
+    pub wd: WatchId,
+    pub event: Event,
+    /// Name of the entry within the watched directory, empty when the event
+    /// is about the watched path itself
+    pub name: String,
+    /// The watched path joined with `name`
+    pub path: PathBuf,
+    /// The old path of a `Renamed` entry, which now lives at `path`
+    #[serde(default)]
+    pub from: Option<PathBuf>,
+    /// When the event was read from the backend; inotify itself records no
+    /// times
+    pub time: SystemTime,
+}
+
+/// A platform's way of watching paths for changes.
+///
+/// Linux watches through inotify, Windows through ReadDirectoryChangesW
+/// (windows::WindowsBackend) and macOS through kqueue
+/// (macos::KQueueBackend). Other platforms have no backend yet and report
+/// `BackendError::Unsupported`.
+pub trait FileWatchBackend {
+    /// Starts watching `path` for the given events, or for a backend
+    /// default set when `events` is empty
+    fn watch(&mut self, path: &Path, events: &[Event]) -> Result<WatchId, BackendError>;
+
+    /// Stops a watch returned by watch()
+    fn unwatch(&mut self, id: WatchId) -> Result<(), BackendError>;
+
+    /// Events that arrived since the last call, without blocking
+    fn read_events(&mut self) -> Result<Vec<FsEvent>, BackendError>;
+}
+
+//Keeps the trait usable as `dyn FileWatchBackend` on every target
+const _: Option<&dyn FileWatchBackend> = None;
+
+/// inotify backed watching on Linux
+#[cfg(target_os = "linux")]
+pub struct INotifyBackend {
+    inotify: INotify,
+    debounce: Duration,
+}
+
+#[cfg(target_os = "linux")]
+impl INotifyBackend {
+    pub fn new() -> Result<Self, BackendError> {
+        return Ok(Self { inotify: INotify::new("")?, debounce: Duration::ZERO });
+    }
+
+    /// Also watches every subdirectory of a watched path, including ones
+    /// created after the watch was added. Applies to later watch() calls.
+    pub fn recursive(&mut self, enabled: bool) -> &mut Self {
+        self.inotify.recursive = enabled;
+        return self;
+    }
+
+    /// Stops the watch on `path`, as passed to watch()
+    pub fn unwatch_path(&mut self, path: &Path) -> Result<(), BackendError> {
+        return Ok(self.inotify.remove_path(&s!(path.display()))?);
+    }
+
+    /// Makes listen() hold each event until its path has seen no others
+    /// for `window`, then send only the latest one. Zero, the default,
+    /// sends every event as it arrives.
+    pub fn debounce(&mut self, window: Duration) -> &mut Self {
+        self.debounce = window;
+        return self;
+    }
+
+    /// Blocks until events are queued or `timeout` passes
+    pub fn wait(&self, timeout: Duration) -> Result<(), BackendError> {
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        return Ok(self.inotify.wait(timeout)?);
+    }
+
+    /// Sends every event over `sender` as it arrives, blocking until the
+    /// receiver is dropped. Run it on its own thread; the receiver can be
+    /// read outside an async runtime with `blocking_recv()`.
+    pub fn listen(&mut self, sender: UnboundedSender<FsEvent>) -> Result<(), BackendError> {
+        return Ok(self.inotify.listen_channel(sender, self.debounce)?);
+    }
+
+    /// listen() handing what each tick makes ready to `tick` until it
+    /// returns false
+    pub(crate) fn listen_with<F: FnMut(Vec<FsEvent>) -> bool>(
+        &mut self, tick: F
+    ) -> Result<(), BackendError> {
+        return Ok(self.inotify.listen_with(self.debounce, tick)?);
+    }
+
+    /// Moves the backend onto its own thread and delivers events in
+    /// batches: everything one read drains, or with debounce() set,
+    /// everything that settles at once. The thread stops once the receiver
+    /// is dropped, or on a read error, which closes the channel.
+    pub fn watch_batched(mut self) -> UnboundedReceiver<Vec<FsEvent>> {
+        let (sender, receiver) = unbounded_channel();
+        std::thread::spawn(move || {
+            let debounce = self.debounce;
+            _ = self.inotify.listen_batches(sender, debounce);
+        });
+        return receiver;
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl FileWatchBackend for INotifyBackend {
+    fn watch(&mut self, path: &Path, events: &[Event]) -> Result<WatchId, BackendError> {
+        return Ok(self.inotify.add(&s!(path.display()), events)?);
+    }
+
+    fn unwatch(&mut self, id: WatchId) -> Result<(), BackendError> {
+        return Ok(self.inotify.remove(id)?);
+    }
+
+    fn read_events(&mut self) -> Result<Vec<FsEvent>, BackendError> {
+        return Ok(self.inotify.read_events()?);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for INotifyBackend {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.inotify.id); }
+    }
+}
+
+/// Holds back events until their path has been quiet for `window`, keeping
+/// only the latest event per path
+#[cfg(target_os = "linux")]
+pub(crate) struct Debouncer {
+    window: Duration,
+    /// Latest event per (watch, name), when it arrived and its arrival order
+    pending: HashMap<(WatchId, String), (FsEvent, Instant, u64)>,
+    received: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl Debouncer {
+    pub(crate) fn new(window: Duration) -> Self {
+        return Self { window, pending: HashMap::new(), received: 0 };
+    }
+
+    /// Replaces any pending event for the same path and restarts its timer
+    pub(crate) fn push(&mut self, event: FsEvent, now: Instant) {
+        self.received += 1;
+        self.pending.insert((event.wd, s!(event.name)), (event, now, self.received));
+    }
+
+    /// Takes the events whose path has been quiet for the window, in the
+    /// order they arrived
+    pub(crate) fn ready(&mut self, now: Instant) -> Vec<FsEvent> {
+        let mut ready = vec![];
+        self.pending.retain(|_, (event, seen, order)| {
+            if now.duration_since(*seen) < self.window { return true; }
+            ready.push((*order, event.clone()));
+            false
+        });
+        ready.sort_by_key(|(order, _)| *order);
+        return ready.into_iter().map(|(_, event)| event).collect();
+    }
+}
+
+/// Stand-in for platforms without a backend; every call is `Unsupported`
+pub struct UnsupportedBackend;
+
+impl FileWatchBackend for UnsupportedBackend {
+    fn watch(&mut self, _path: &Path, _events: &[Event]) -> Result<WatchId, BackendError> {
+        return Err(BackendError::Unsupported);
+    }
+
+    fn unwatch(&mut self, _id: WatchId) -> Result<(), BackendError> {
+        return Err(BackendError::Unsupported);
+    }
+
+    fn read_events(&mut self) -> Result<Vec<FsEvent>, BackendError> {
+        return Err(BackendError::Unsupported);
+    }
+}
+
+/// The backend for the current platform
+pub fn default_backend() -> Result<Box<dyn FileWatchBackend>, BackendError> {
+    #[cfg(target_os = "linux")]
+    {
+        return Ok(Box::new(INotifyBackend::new()?));
+    }
+    #[cfg(target_os = "windows")]
+    {
+        return Ok(Box::new(crate::windows::WindowsBackend::new()?));
+    }
+    #[cfg(target_os = "macos")]
+    {
+        return Ok(Box::new(crate::macos::KQueueBackend::new()?));
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+    {
+        return Err(BackendError::Unsupported);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(target_os = "linux")]
+    use crate::{watcher::Watcher, N};
+
+    #[test]
+    fn backends_are_usable_as_trait_objects_on_every_target() {
+        let mut unsupported: Box<dyn FileWatchBackend> = Box::new(UnsupportedBackend);
+        assert!(matches!(unsupported.watch(Path::new("."), &[]), Err(BackendError::Unsupported)));
+        assert!(matches!(unsupported.read_events(), Err(BackendError::Unsupported)));
+
+        let default = default_backend();
+        #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
+        assert!(default.is_ok());
+        #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+        assert!(matches!(default, Err(BackendError::Unsupported)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn listen_sends_events_and_stops_once_the_receiver_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut backend = INotifyBackend::new().unwrap();
+        backend.watch(dir.path(), &[]).unwrap();
+        let (sender, mut receiver) = unbounded_channel();
+        let listener = std::thread::spawn(move || backend.listen(sender));
+
+        std::fs::write(dir.path().join("new"), "").unwrap();
+        let event = receiver.blocking_recv().unwrap();
+        assert!(matches!(event.event, Event::Create));
+        assert_eq!(event.name, "new");
+        assert_eq!(event.path, dir.path().join("new"));
+
+        // Nothing else happens on disk, so only the idle tick can notice
+        drop(receiver);
+        listener.join().unwrap().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watch_batched_delivers_one_read_as_one_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher: Watcher<N, N> = Watcher::new(&s!(dir.path().display())).unwrap();
+        watcher.walk().unwrap();
+        let mut backend = INotifyBackend::new().unwrap();
+        backend.watch(dir.path(), &[Event::Create]).unwrap();
+
+        // Queued before the listener starts, so its first read drains all three
+        for name in ["a", "b", "c"] { std::fs::write(dir.path().join(name), "").unwrap(); }
+        let mut receiver = backend.watch_batched();
+        let batch = receiver.blocking_recv().unwrap();
+        let names: Vec<_> = batch.iter().map(|event| event.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+
+        assert!(watcher.dir_info.apply_events(&batch));
+        assert_eq!(watcher.dir_info.flatten_all().len(), 3);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn debouncing_coalesces_a_burst_into_one_event_after_the_quiet_period() {
+        let window = Duration::from_millis(100);
+        let modify = |name: &str| FsEvent {
+            wd: 1, event: Event::Modify, name: s!(name), path: PathBuf::from("/d").join(name),
+            from: None, time: SystemTime::now(),
+        };
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(window);
+        for ms in [0, 30, 60] {
+            debouncer.push(modify("saved"), start + Duration::from_millis(ms));
+        }
+        debouncer.push(modify("other"), start + Duration::from_millis(10));
+
+        // Quiet for the window since the last push for "other" only
+        let ready = debouncer.ready(start + Duration::from_millis(120));
+        assert_eq!(ready.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(), ["other"]);
+
+        let ready = debouncer.ready(start + Duration::from_millis(160));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].name, "saved");
+        assert!(debouncer.ready(start + Duration::from_secs(1)).is_empty());
+    }
+}
@@ -1,8 +1,15 @@
 use std::{
     cmp::Ordering, collections::HashMap, hash::Hash, path::{Path, PathBuf}, 
-    time::SystemTime, 
+    time::{Duration, SystemTime}, 
 };
+#[cfg(feature = "ratatui")]
+use ratatui::{style::{Color, Modifier, Style}, text::{Line, Span}};
 use serde::{Deserialize, Serialize};
+use crate::{util::{escape_dot, escape_html, escape_markdown, format_bytes}, watcher::SNAPSHOT_FILE};
+#[cfg(feature = "content-hash")]
+use std::io;
+#[cfg(target_os = "linux")]
+use crate::{backend::FsEvent, inotify::Event, watcher::node_from_disk};
 use dekor::*;
 use simplicio::*;
 
@@ -13,6 +20,23 @@ pub enum FsNodeError {
     InvalidName,
 }
 
+/// A single difference between two walks of the same tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added(PathBuf),
+    Removed(PathBuf),
+    Modified(PathBuf),
+}
+
+/// Working tree status of a file in a git repository
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GitStatus {
+    Clean,
+    Modified,
+    Added,
+    Untracked,
+}
+
 #[repr(i32)]
 #[derive(Clone, PartialEq, Eq, Serialize, Hash, Deserialize, Debug)]
 pub enum N {N}
@@ -32,18 +56,21 @@ impl std::fmt::Display for FsNodeError {
     }
 }
 
+impl std::error::Error for FsNodeError {}
 
-#[derive(Debug, Deserialize, Serialize)]
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum FsNode<K, V> where K: Hash + Eq + Clone, V: Clone {
     Directory(DirInfo<K, V>),
     File(FileInfo<K, V>),
+    Symlink(SymlinkInfo),
 }
 
 impl<K, V> FsNode<K, V> where K: Hash + Eq + Clone, V: Clone {
     pub fn is_dir(&self) -> bool {
         match self {
             FsNode::Directory(_) => true,
-            FsNode::File(_) => false,
+            FsNode::File(_) | FsNode::Symlink(_) => false,
         }
     }
 
@@ -51,6 +78,7 @@ impl<K, V> FsNode<K, V> where K: Hash + Eq + Clone, V: Clone {
         match self {
             FsNode::Directory(d) => s!(d.name),
             FsNode::File(f) => s!(f.name),
+            FsNode::Symlink(l) => s!(l.name),
         }
     }
 
@@ -58,6 +86,50 @@ impl<K, V> FsNode<K, V> where K: Hash + Eq + Clone, V: Clone {
         match self {
             FsNode::Directory(d) => d.path.clone(),
             FsNode::File(f) => f.path.clone(),
+            FsNode::Symlink(l) => l.path.clone(),
+        }
+    }
+
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        match self {
+            FsNode::Directory(d) => d.last_modified,
+            FsNode::File(f) => f.last_modified,
+            FsNode::Symlink(l) => l.last_modified,
+        }
+    }
+
+    pub(crate) fn name_ref(&self) -> &str {
+        match self {
+            FsNode::Directory(d) => &d.name,
+            FsNode::File(f) => &f.name,
+            FsNode::Symlink(l) => &l.name,
+        }
+    }
+
+    pub(crate) fn path_ref(&self) -> &Path {
+        match self {
+            FsNode::Directory(d) => &d.path,
+            FsNode::File(f) => &f.path,
+            FsNode::Symlink(l) => &l.path,
+        }
+    }
+
+    /// Moves the node to `path`, renaming it and rewriting the paths of
+    /// everything beneath it
+    #[cfg(target_os = "linux")]
+    fn rebase(&mut self, path: &Path) {
+        let name = path.file_name().map(|name| s!(name.to_string_lossy())).unwrap_or_default();
+        match self {
+            FsNode::Directory(d) => {
+                d.name = name;
+                d.path = path.to_path_buf();
+                for node in d.content.iter_mut() {
+                    let child = path.join(node.name_ref());
+                    node.rebase(&child);
+                }
+            },
+            FsNode::File(f) => { f.name = name; f.path = path.to_path_buf(); },
+            FsNode::Symlink(l) => { l.name = name; l.path = path.to_path_buf(); },
         }
     }
 }
@@ -67,22 +139,29 @@ impl<K, V> Clone for FsNode<K, V> where K: Hash + Eq + Clone, V: Clone {
         match self {
             FsNode::Directory(d) => FsNode::Directory(d.clone()),
             FsNode::File(f) => FsNode::File(f.clone()),
+            FsNode::Symlink(l) => FsNode::Symlink(l.clone()),
         }
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DirInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
     pub name: String,
     pub path: PathBuf,
     pub last_modified: Option<SystemTime>,
+    /// Missing from older snapshots, which load expanded like a fresh walk
+    #[serde(default = "expanded_default")]
+    pub expanded: bool,
+    /// Permission bits on Unix, file attribute flags on Windows
+    #[serde(default)]
+    pub mode: Option<u32>,
     pub content: Vec<FsNode<K, V>>,
     pub fields: Option<HashMap<K, V>>,
 }
 
 impl<K, V> DirInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
     pub fn new(
-        path: &str, last_modified: Option<SystemTime>,
+        path: &str, last_modified: Option<SystemTime>, expanded: bool,
         content: Vec<FsNode<K, V>>, fields: Option<HashMap<K, V>>
     ) -> Result<Self, FsNodeError> {
         let path = if path.is_empty() {
@@ -102,7 +181,8 @@ impl<K, V> DirInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
             name: s!(name), 
             path, 
             last_modified, 
-        
+            expanded,
+            mode: None,
             content, 
             fields,
         })
@@ -126,6 +206,8 @@ impl<K, V> DirInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
             name: s!(name), 
             path, 
             last_modified: None, 
+            expanded: true,
+            mode: None,
             content: vec![], 
             fields: None,
         })
@@ -157,6 +239,8 @@ impl<K, V> DirInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
     /// // Returns Some(Greater) if time_now is greater than dir_info.time
     /// // Returns `None` if `dir_info` does not have a set `last_modified`
     /// let compared_time = dir_info.cmp(time_now);
+    // Compares against a time rather than another node, so it isn't Ord::cmp
+    #[allow(clippy::should_implement_trait)]
     pub fn cmp(&self, time: &SystemTime) -> Option<Ordering> {
         match self.last_modified {
             Some(last_time) => return Some(last_time.cmp(time)),
@@ -178,36 +262,629 @@ impl<K, V> DirInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
         self.content.retain(|n| n.path() != path);
     }
 
+    /// remove() for a node anywhere beneath this directory, returning
+    /// whether one was removed
+    pub fn remove_recursive(&mut self, path: &Path) -> bool {
+        let Some(parent) = path.parent().and_then(|p| self.find_dir_mut(p)) else {
+            return false;
+        };
+        let count = parent.content.len();
+        parent.content.retain(|node| node.path_ref() != path);
+        return parent.content.len() != count;
+    }
+
+    /// Adds `node` to the directory at `parent` anywhere in the tree, this
+    /// directory included, keeping its content sorted by name. Returns
+    /// false when no such directory exists.
+    pub fn insert_at(&mut self, parent: &Path, node: FsNode<K, V>) -> bool {
+        let Some(dir) = self.find_dir_mut(parent) else { return false; };
+        dir.content.push(node);
+        dir.content.sort_by(|a, b| a.name_ref().cmp(b.name_ref()));
+        return true;
+    }
+
     pub fn set_fields(&mut self, fields: Option<HashMap<K, V>>) -> &mut Self {
         self.fields = fields;
         return self;
     }
 
+    /// Unions `other` into this tree by path. Entries only in `other` are
+    /// added, matching directories are merged recursively and matching
+    /// files take `other`'s metadata; an entry that changed kind is replaced.
+    /// Fields are merged key by key with `other`'s values winning, and
+    /// `expanded` is kept from this tree.
+    pub fn merge(&mut self, other: DirInfo<K, V>) {
+        self.last_modified = other.last_modified.or(self.last_modified);
+        self.mode = other.mode.or(self.mode);
+        self.fields = merge_fields(self.fields.take(), other.fields);
+
+        for node in other.content {
+            let existing = self.content.iter_mut().find(|n| n.path_ref() == node.path_ref());
+            match (existing, node) {
+                (Some(FsNode::Directory(ours)), FsNode::Directory(theirs)) => ours.merge(theirs),
+                (Some(FsNode::File(ours)), FsNode::File(mut theirs)) => {
+                    theirs.fields = merge_fields(ours.fields.take(), theirs.fields);
+                    *ours = theirs;
+                },
+                (Some(ours), theirs) => *ours = theirs,
+                (None, theirs) => self.content.push(theirs),
+            }
+        }
+        self.content.sort_by(|a, b| a.name_ref().cmp(b.name_ref()));
+    }
+
     pub fn add_field(&mut self, key: K, value: V) -> &mut Self {
         match self.fields.as_mut() {
             Some(map) => { map.insert(key, value); },
             None => self.fields = Some(map!(key : value)),
         }
-        return self.into();
+        return self;
+    }
+
+    pub fn get_field(&self, key: &K) -> Option<&V> {
+        return self.fields.as_ref().and_then(|map| map.get(key));
+    }
+
+    pub fn contains_field(&self, key: &K) -> bool {
+        return self.fields.as_ref().is_some_and(|map| map.contains_key(key));
+    }
+
+    /// Removes `key`, resetting `fields` to `None` once the map is empty
+    pub fn remove_field(&mut self, key: &K) -> Option<V> {
+        let map = self.fields.as_mut()?;
+        let value = map.remove(key);
+        if map.is_empty() { self.fields = None; }
+        return value;
+    }
+
+    /// Number of files and directories beneath this directory, not counting itself
+    pub fn entry_count(&self) -> usize {
+        return self.content.iter().map(|node| match node {
+            FsNode::Directory(d) => 1 + d.entry_count(),
+            FsNode::File(_) | FsNode::Symlink(_) => 1,
+        }).sum();
+    }
+
+    /// Every node beneath this directory in depth-first pre-order,
+    /// descending into collapsed directories too
+    pub fn iter(&self) -> FsNodeIter<'_, K, V> {
+        return FsNodeIter::new(self);
+    }
+
+    /// iter() paired with each node's depth, where direct children are 1
+    pub fn iter_with_depth(&self) -> FsNodeDepthIter<'_, K, V> {
+        return FsNodeDepthIter(FsNodeIter::new(self));
+    }
+
+    /// (file_count, dir_count) for the whole subtree, with this directory
+    /// itself included in dir_count when `include_self` is set. Unfollowed
+    /// symlinks count as files.
+    pub fn counts(&self, include_self: bool) -> (usize, usize) {
+        let mut counts = (0, include_self as usize);
+        for node in self.content.iter() {
+            match node {
+                FsNode::Directory(d) => {
+                    let (files, dirs) = d.counts(true);
+                    counts.0 += files;
+                    counts.1 += dirs;
+                },
+                FsNode::File(_) | FsNode::Symlink(_) => counts.0 += 1,
+            }
+        }
+        return counts;
+    }
+
+    /// Counts files by age (`now` minus `last_modified`) against ascending
+    /// `buckets` of upper bounds. The result has one more slot than
+    /// `buckets`, holding files older than every bound. Files without a
+    /// time are skipped, and files newer than `now` land in the first slot.
+    pub fn age_histogram(&self, now: SystemTime, buckets: &[Duration]) -> Vec<usize> {
+        let mut histogram = vec![0; buckets.len() + 1];
+        for node in self.iter() {
+            let modified = match node {
+                FsNode::File(f) => match f.last_modified {
+                    Some(modified) => modified,
+                    None => continue,
+                },
+                FsNode::Directory(_) | FsNode::Symlink(_) => continue,
+            };
+            let age = now.duration_since(modified).unwrap_or_default();
+            let slot = buckets.iter().position(|b| age < *b).unwrap_or(buckets.len());
+            histogram[slot] += 1;
+        }
+        return histogram;
+    }
+
+    /// Counts the files beneath this directory by lowercased extension;
+    /// files without one are counted under `""`
+    pub fn extension_histogram(&self) -> HashMap<String, usize> {
+        let mut histogram = HashMap::new();
+        for node in self.iter() {
+            let FsNode::File(f) = node else { continue; };
+            let ext = f.path.extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            *histogram.entry(ext).or_insert(0) += 1;
+        }
+        return histogram;
+    }
+
+    /// Sum of every file size beneath this directory, counting unknown sizes as 0
+    pub fn total_size(&self) -> u64 {
+        return self.content.iter().map(|node| match node {
+            FsNode::Directory(d) => d.total_size(),
+            FsNode::File(f) => f.size.unwrap_or(0),
+            FsNode::Symlink(_) => 0,
+        }).sum();
+    }
+
+    /// The newest `last_modified` of this directory and everything beneath
+    /// it, or `None` when none of them has one
+    pub fn latest_modified(&self) -> Option<SystemTime> {
+        return self.iter().filter_map(|node| node.last_modified()).chain(self.last_modified).max();
+    }
+
+    /// Splits the direct children into up to `n` standalone trees sharing this
+    /// directory's root, balanced by total size. When no sizes are known
+    /// they are balanced by entry count instead.
+    pub fn shards(&self, n: usize) -> Vec<DirInfo<K, V>> {
+        let by_size = self.total_size() > 0;
+        let mut children: Vec<(u64, &FsNode<K, V>)> = self.content.iter()
+            .map(|node| (match node {
+                FsNode::Directory(d) if by_size => d.total_size(),
+                FsNode::Directory(d) => 1 + d.entry_count() as u64,
+                FsNode::File(f) if by_size => f.size.unwrap_or(0),
+                FsNode::Symlink(_) if by_size => 0,
+                FsNode::File(_) | FsNode::Symlink(_) => 1,
+            }, node))
+            .collect();
+        //Heaviest first, each into the currently lightest shard
+        children.sort_by_key(|(weight, _)| std::cmp::Reverse(*weight));
+
+        let mut shards: Vec<(u64, DirInfo<K, V>)> = (0..n.min(children.len()))
+            .map(|_| (0, self.empty_clone()))
+            .collect();
+        for (weight, node) in children {
+            if let Some((total, shard)) = shards.iter_mut().min_by_key(|(total, _)| *total) {
+                *total += weight;
+                shard.content.push(node.clone());
+            }
+        }
+
+        return shards.into_iter().map(|(_, shard)| shard).collect();
+    }
+
+    /// Sorts the content of this directory and every directory beneath it,
+    /// files and directories together. Ties fall back to the name.
+    pub fn sort(&mut self, key: SortKey) {
+        for node in self.content.iter_mut() {
+            if let FsNode::Directory(d) = node { d.sort(key); }
+        }
+        self.content.sort_by(|a, b| key.compare(a, b).then_with(|| a.name_ref().cmp(b.name_ref())));
+    }
+
+    /// The subdirectory with the greatest total_size(). A directory is never
+    /// smaller than its own subdirectories, so this is always a direct child.
+    /// Ties go to the first by name.
+    pub fn largest_dir(&self) -> Option<&DirInfo<K, V>> {
+        return self.content.iter()
+            .filter_map(|node| match node {
+                FsNode::Directory(d) => Some((d.total_size(), d)),
+                FsNode::File(_) | FsNode::Symlink(_) => None,
+            })
+            .max_by(|(a_size, a), (b_size, b)| a_size.cmp(b_size).then_with(|| b.name.cmp(&a.name)))
+            .map(|(_, d)| d);
+    }
+
+    /// Path of every file beneath this directory, depth-first in content
+    /// order
+    pub fn flatten(&self) -> Vec<PathBuf> {
+        return self.iter().filter(|node| !node.is_dir()).map(|node| node.path()).collect();
+    }
+
+    /// flatten() including directory paths, each before its content
+    pub fn flatten_all(&self) -> Vec<PathBuf> {
+        return self.iter().map(|node| node.path()).collect();
+    }
+
+    /// Every node beneath this directory whose name contains `query`
+    pub fn search_by_name(&self, query: &str) -> Vec<&FsNode<K, V>> {
+        return self.iter().filter(|node| node.name().contains(query)).collect();
+    }
+
+    /// Case-insensitive search_by_name()
+    pub fn search_by_name_ci(&self, query: &str) -> Vec<&FsNode<K, V>> {
+        let query = query.to_lowercase();
+        return self.iter()
+            .filter(|node| node.name().to_lowercase().contains(&query))
+            .collect();
+    }
+
+    /// How many levels below this directory `path` sits in the tree: 0 for
+    /// the directory itself, 1 for its children. `None` if it isn't found.
+    pub fn depth_of(&self, path: &Path) -> Option<usize> {
+        if self.path == path { return Some(0); }
+        return self.iter_with_depth()
+            .find(|(_, node)| node.path_ref() == path)
+            .map(|(depth, _)| depth);
+    }
+
+    /// Finds the node at `path` anywhere beneath this directory. The
+    /// directory itself isn't an `FsNode`, so its own path returns `None`.
+    pub fn find(&self, path: &Path) -> Option<&FsNode<K, V>> {
+        for node in self.content.iter() {
+            if node.path_ref() == path { return Some(node); }
+            if let FsNode::Directory(d) = node {
+                if path.starts_with(&d.path) { return d.find(path); }
+            }
+        }
+        return None;
+    }
+
+    /// Mutable find()
+    pub fn find_mut(&mut self, path: &Path) -> Option<&mut FsNode<K, V>> {
+        for node in self.content.iter_mut() {
+            if node.path_ref() == path { return Some(node); }
+            if let FsNode::Directory(d) = node {
+                if path.starts_with(&d.path) { return d.find_mut(path); }
+            }
+        }
+        return None;
+    }
+
+    /// find_mut() under the name callers of HashMap-like lookups expect, for
+    /// toggling `expanded` or editing the fields of a node in place
+    pub fn get_mut(&mut self, path: &Path) -> Option<&mut FsNode<K, V>> {
+        return self.find_mut(path);
+    }
+
+    /// Finds the directory at `path`, including this directory itself
+    pub(crate) fn find_dir(&self, path: &Path) -> Option<&DirInfo<K, V>> {
+        if self.path == path { return Some(self); }
+        if !path.starts_with(&self.path) { return None; }
+        return self.content.iter().find_map(|node| match node {
+            FsNode::Directory(d) => d.find_dir(path),
+            FsNode::File(_) | FsNode::Symlink(_) => None,
+        });
+    }
+
+    /// find_dir() returning the directory mutably
+    pub(crate) fn find_dir_mut(&mut self, path: &Path) -> Option<&mut DirInfo<K, V>> {
+        if self.path == path { return Some(self); }
+        if !path.starts_with(&self.path) { return None; }
+        return self.content.iter_mut().find_map(|node| match node {
+            FsNode::Directory(d) => d.find_dir_mut(path),
+            FsNode::File(_) | FsNode::Symlink(_) => None,
+        });
+    }
+
+    /// Applies one filesystem event to the tree, returning whether it
+    /// changed. `Create` adds the entry at its parent directory as it is on
+    /// disk, `Delete` removes it and `Modify` refreshes its mtime and size.
+    /// `Renamed` moves the entry from its old path along with everything
+    /// beneath it, or adds it like `Create` when the old path isn't in the
+    /// tree. An unpaired `MovedFrom` removes the entry and `MovedTo` adds it
+    /// anew; directories are added without their content either way. Other
+    /// events change nothing.
+    #[cfg(target_os = "linux")]
+    pub fn apply_event(&mut self, event: &FsEvent) -> bool {
+        let path = event.path.as_path();
+        if path == self.path { return false; }
+
+        match event.event {
+            Event::Create | Event::MovedTo => {
+                if self.find(path).is_some() { return false; }
+                let Some(parent) = path.parent() else { return false; };
+                if self.find_dir(parent).is_none() { return false; }
+                let Ok(node) = node_from_disk(path) else { return false; };
+                return self.insert_at(parent, node);
+            },
+            Event::Delete | Event::MovedFrom => return self.remove_recursive(path),
+            Event::Renamed => {
+                let Some(from) = event.from.as_deref() else { return false; };
+                let Some(old_parent) = from.parent().and_then(|p| self.find_dir_mut(p)) else {
+                    return self.apply_event(&FsEvent { event: Event::Create, ..event.clone() });
+                };
+                let Some(index) = old_parent.content.iter().position(|node| node.path_ref() == from) else {
+                    return self.apply_event(&FsEvent { event: Event::Create, ..event.clone() });
+                };
+                let mut node = old_parent.content.remove(index);
+                // Replaces whatever the entry was moved over
+                self.remove_recursive(path);
+                node.rebase(path);
+                if let Some(parent) = path.parent() { self.insert_at(parent, node); }
+                return true;
+            },
+            Event::Modify => {
+                let Ok(metadata) = std::fs::symlink_metadata(path) else { return false; };
+                let modified = metadata.modified().ok();
+                return match self.find_mut(path) {
+                    Some(FsNode::File(f)) => {
+                        let changed = f.last_modified != modified || f.size != Some(metadata.len());
+                        f.last_modified = modified;
+                        f.size = Some(metadata.len());
+                        changed
+                    },
+                    Some(FsNode::Directory(d)) => {
+                        let changed = d.last_modified != modified;
+                        d.last_modified = modified;
+                        changed
+                    },
+                    _ => false,
+                };
+            },
+            _ => return false,
+        }
+    }
+
+    /// apply_event() for each of `events` in order, returning whether any
+    /// of them changed the tree
+    #[cfg(target_os = "linux")]
+    pub fn apply_events(&mut self, events: &[FsEvent]) -> bool {
+        let mut changed = false;
+        for event in events { changed |= self.apply_event(event); }
+        return changed;
+    }
+
+    /// Copy of the tree holding only `paths` and the directories leading to
+    /// them. A listed directory keeps all of its content; paths that aren't
+    /// in the tree are ignored.
+    pub fn tree_of_paths(&self, paths: &[PathBuf]) -> DirInfo<K, V> {
+        let mut tree = self.empty_clone();
+
+        for node in self.content.iter() {
+            let path = node.path();
+            if paths.contains(&path) {
+                tree.content.push(node.clone());
+                continue;
+            }
+
+            if let FsNode::Directory(d) = node {
+                if !paths.iter().any(|p| p.starts_with(&path)) { continue; }
+                let subtree = d.tree_of_paths(paths);
+                if !subtree.content.is_empty() {
+                    tree.content.push(FsNode::Directory(subtree));
+                }
+            }
+        }
+
+        return tree;
+    }
+
+    /// Hash of every file's contents together with the tree's structure,
+    /// keyed by paths relative to this directory so identical trees at
+    /// different locations compare equal. Reads every file from disk. The
+    /// BLAKE3 digest it is folded from is stable across Rust releases and
+    /// platforms, so it can be stored and compared later.
+    #[cfg(feature = "content-hash")]
+    pub fn content_checksum(&self) -> io::Result<u64> {
+        let mut entries: Vec<(String, Option<blake3::Hash>)> = vec![];
+        checksum_recursion(self, &self.path, &mut entries)?;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut hasher = blake3::Hasher::new();
+        for (path, hash) in entries {
+            // Prefixed with its length so no path runs into the next entry
+            hasher.update(&(path.len() as u64).to_le_bytes());
+            hasher.update(path.as_bytes());
+            match hash {
+                Some(hash) => hasher.update(&[1]).update(hash.as_bytes()),
+                None => hasher.update(&[0]),
+            };
+        }
+        let digest = hasher.finalize();
+        let (head, _) = digest.as_bytes().split_at(8);
+        return Ok(u64::from_le_bytes(head.try_into().unwrap()));
+    }
+
+    /// Copy of the tree keeping only files that match `pred`, plus the
+    /// directories leading to them. Directories left empty and symlinks are
+    /// dropped.
+    pub fn filter<F: Fn(&FileInfo<K, V>) -> bool>(&self, pred: F) -> DirInfo<K, V> {
+        return self.filter_by(&pred);
+    }
+
+    fn filter_by<F: Fn(&FileInfo<K, V>) -> bool>(&self, pred: &F) -> DirInfo<K, V> {
+        let mut tree = self.empty_clone();
+        for node in self.content.iter() {
+            match node {
+                FsNode::Directory(d) => {
+                    let subtree = d.filter_by(pred);
+                    if !subtree.content.is_empty() {
+                        tree.content.push(FsNode::Directory(subtree));
+                    }
+                },
+                FsNode::File(f) => if pred(f) { tree.content.push(node.clone()) },
+                FsNode::Symlink(_) => {},
+            }
+        }
+        return tree;
+    }
+
+    /// Drops every node that fails `pred`, in place. A directory is checked
+    /// before its content, so one that fails is dropped along with
+    /// everything beneath it; kept directories are then filtered in turn.
+    pub fn retain<F: Fn(&FsNode<K, V>) -> bool>(&mut self, pred: F) {
+        self.retain_by(&pred);
+    }
+
+    fn retain_by<F: Fn(&FsNode<K, V>) -> bool>(&mut self, pred: &F) {
+        self.content.retain_mut(|node| {
+            if !pred(node) { return false; }
+            if let FsNode::Directory(d) = node { d.retain_by(pred); }
+            true
+        });
+    }
+
+    /// Removes every subdirectory with no files anywhere beneath it, in
+    /// place. Symlinks count as files; directories cut off by a max depth
+    /// have no content and are removed too.
+    pub fn prune_empty(&mut self) {
+        self.content.retain_mut(|node| match node {
+            FsNode::Directory(d) => {
+                d.prune_empty();
+                !d.content.is_empty()
+            },
+            FsNode::File(_) | FsNode::Symlink(_) => true,
+        });
+    }
+
+    /// Clone of this directory without its content
+    fn empty_clone(&self) -> Self {
+        Self {
+            name: s!(self.name),
+            path: self.path.clone(),
+            last_modified: self.last_modified,
+            expanded: self.expanded,
+            mode: self.mode,
+            content: vec![],
+            fields: self.fields.clone(),
+        }
+    }
+
+    /// Whether the recorded mode marks this directory read-only; `false`
+    /// when no mode was recorded
+    pub fn is_readonly(&self) -> bool {
+        return self.mode.is_some_and(mode_is_readonly);
+    }
+
+    pub fn set_expanded(&mut self, expanded: bool) -> &mut Self {
+        self.expanded = expanded;
+        return self;
     }
 
     pub fn build_tree(&self) -> Vec<String> {
-        let mut tree: Vec<String> = Vec::new();
-
-        tree.push(s!(
-            "[", 
-            style!(Bold, FGGreen => 
-                Utf8::ModLetterDownArrowhead), // ˅ ˃
-            "]",
-            style!(Bold, FGBlue => self.name),
+        return self.build_tree_with(&TreeConfig::default());
+    }
+
+    /// build_tree() without ANSI escape codes, for output that isn't a
+    /// color terminal
+    pub fn build_tree_plain(&self) -> Vec<String> {
+        return self.build_tree_with(&TreeConfig { color: false, ..Default::default() });
+    }
+
+    /// build_tree() drawn with the given glyphs
+    pub fn build_tree_styled(&self, style: &TreeStyle) -> Vec<String> {
+        return self.build_tree_with(&TreeConfig { style: style.clone(), ..Default::default() });
+    }
+
+    /// build_tree() with entries in the given order
+    pub fn build_tree_ordered(&self, order: SortOrder) -> Vec<String> {
+        return self.build_tree_with(&TreeConfig { order, ..Default::default() });
+    }
+
+    /// build_tree() showing only `max_depth` levels below this directory,
+    /// where 1 is just its children. Directories with hidden content end
+    /// with an ellipsis; `expanded` is left untouched.
+    pub fn build_tree_depth(&self, max_depth: usize) -> Vec<String> {
+        return self.build_tree_with(&TreeConfig { max_depth: Some(max_depth), ..Default::default() });
+    }
+
+    /// build_tree() with human-readable sizes after every entry
+    pub fn build_tree_with_sizes(&self) -> Vec<String> {
+        return self.build_tree_with(&TreeConfig { sizes: true, ..Default::default() });
+    }
+
+    /// build_tree() rendered with the given options
+    pub fn build_tree_with(&self, config: &TreeConfig) -> Vec<String> {
+        return self.tree_lines(config).into_iter()
+            .map(|line| line.iter().map(|segment| segment.ansi(config.color)).collect())
+            .collect();
+    }
+
+    /// build_tree() as styled ratatui lines instead of ANSI strings
+    #[cfg(feature = "ratatui")]
+    pub fn build_tree_lines(&self) -> Vec<Line<'static>> {
+        return self.build_tree_lines_with(&TreeConfig::default());
+    }
+
+    /// build_tree_with() as styled ratatui lines, unstyled when `color` is
+    /// off
+    #[cfg(feature = "ratatui")]
+    pub fn build_tree_lines_with(&self, config: &TreeConfig) -> Vec<Line<'static>> {
+        return self.tree_lines(config).into_iter()
+            .map(|line| Line::from(
+                line.into_iter().map(|segment| segment.span(config.color)).collect::<Vec<_>>()
+            ))
+            .collect();
+    }
+
+    /// The lines of build_tree_with() before styling, shared by every
+    /// output it is drawn into
+    fn tree_lines(&self, config: &TreeConfig) -> Vec<Vec<Segment>> {
+        let mut tree: Vec<TreeLine> = Vec::new();
+
+        let size = config.sizes.then(|| self.total_size());
+        let truncated = config.max_depth == Some(0);
+        tree.push((dir_segments(s!(), self, config, truncated), size));
+        if self.expanded && !truncated { tree_recursion(self, s!(), &mut tree, config, 1); }
+
+        return align_sizes(tree);
+    }
+
+    /// The tree as nested `<ul>` lists for styling with CSS. Entries are
+    /// `<li>` items with `class="dir"`, `"file"` or `"symlink"`, and
+    /// directories carry `data-expanded`; every directory is included
+    /// whatever its flag. Names are HTML-escaped.
+    pub fn to_html(&self) -> String {
+        let mut html = s!("<ul>");
+        html_recursion(self, &mut html);
+        html.push_str("</ul>");
+        return html;
+    }
+
+    /// The tree as a Markdown bullet list, indented two spaces per level
+    /// with directories in bold. Names are escaped so characters such as
+    /// `_` and `*` show as written.
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = s!();
+        markdown_recursion(self, 0, &mut markdown);
+        return markdown;
+    }
+
+    /// The tree as a Graphviz `digraph`, with each node identified by its
+    /// path and labelled with its name. Directories are folders, files and
+    /// symlinks are boxes, and edges run from parent to child.
+    pub fn to_dot(&self) -> String {
+        let mut dot = s!("digraph tree {\n");
+        dot.push_str(&format!(
+            "  \"{}\" [label=\"{}\", shape=folder];\n",
+            escape_dot(&s!(self.path.display())), escape_dot(&self.name)
         ));
-        tree_recursion(self, s!(), &mut tree);
-        tree
+        dot_recursion(self, &mut dot);
+        dot.push_str("}\n");
+        return dot;
     }
 
     pub fn build(&self) -> Self {
         return self.clone();
     }
+
+    /// Changes from this tree to `newer`, matching nodes by path. Added and
+    /// removed directories are reported once at the directory level rather
+    /// than per child, and a file is modified when its `last_modified`
+    /// differs. A node that switched between file and directory is reported
+    /// as removed and added. The snapshot file at this root is never
+    /// reported.
+    pub fn diff(&self, newer: &DirInfo<K, V>) -> Vec<Change> {
+        let mut changes = vec![];
+        diff_recursion(self, newer, &self.path.join(SNAPSHOT_FILE), &mut changes);
+        return changes;
+    }
+}
+
+impl<K, V> DirInfo<K, V> where K: Hash + Eq + Clone + Serialize, V: Clone + Serialize {
+    /// JSON patch turning `prev` into this tree, as an array of
+    /// `{"op", "path", "value"}` operations keyed by filesystem path:
+    /// - `add` carries the whole new node, including a directory's content
+    /// - `remove` drops the node and everything beneath it
+    /// - `replace` carries a changed file, or a changed directory without
+    ///   its content (its children are patched separately)
+    pub fn json_patch(&self, prev: &DirInfo<K, V>) -> serde_json::Value {
+        let mut ops = vec![];
+        patch_recursion(prev, self, &mut ops);
+        return serde_json::Value::Array(ops);
+    }
 }
 
 impl<K, V> Clone for DirInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
@@ -216,17 +893,68 @@ impl<K, V> Clone for DirInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
             name: s!(self.name),
             path: self.path.clone(),
             last_modified: self.last_modified,
+            expanded: self.expanded,
+            mode: self.mode,
             content: self.content.clone(),
             fields: self.fields.clone(),
         }
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FsNodeIter<'a, K, V> where K: Hash + Eq + Clone, V: Clone {
+    stack: Vec<(usize, &'a FsNode<K, V>)>,
+}
+
+impl<'a, K, V> FsNodeIter<'a, K, V> where K: Hash + Eq + Clone, V: Clone {
+    fn new(dir_info: &'a DirInfo<K, V>) -> Self {
+        Self { stack: dir_info.content.iter().rev().map(|n| (1, n)).collect() }
+    }
+
+    fn next_with_depth(&mut self) -> Option<(usize, &'a FsNode<K, V>)> {
+        let (depth, node) = self.stack.pop()?;
+        if let FsNode::Directory(d) = node {
+            self.stack.extend(d.content.iter().rev().map(|n| (depth + 1, n)));
+        }
+        return Some((depth, node));
+    }
+}
+
+impl<'a, K, V> Iterator for FsNodeIter<'a, K, V> where K: Hash + Eq + Clone, V: Clone {
+    type Item = &'a FsNode<K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.next_with_depth().map(|(_, node)| node);
+    }
+}
+
+pub struct FsNodeDepthIter<'a, K, V>(FsNodeIter<'a, K, V>) where K: Hash + Eq + Clone, V: Clone;
+
+impl<'a, K, V> Iterator for FsNodeDepthIter<'a, K, V> where K: Hash + Eq + Clone, V: Clone {
+    type Item = (usize, &'a FsNode<K, V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.0.next_with_depth();
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FileInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
     pub name: String,
     pub path: PathBuf,
     pub last_modified: Option<SystemTime>,
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub executable: bool,
+    /// Permission bits on Unix, file attribute flags on Windows
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Set by Watcher::annotate_git_status()
+    #[serde(default)]
+    pub git_status: Option<GitStatus>,
+    /// Hex digest of the contents, set by walks with Watcher::with_hashing()
+    #[serde(default)]
+    pub hash: Option<String>,
     pub fields: Option<HashMap<K, V>>,
 }
 
@@ -236,7 +964,8 @@ impl<K, V> FileInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
         Option<SystemTime>, fields: Option<HashMap<K, V>>
     ) -> Self {
         Self {
-            name, path, last_modified, fields,
+            name, path, last_modified, size: None, executable: false, mode: None,
+            git_status: None, hash: None, fields,
         }
     }
 
@@ -263,6 +992,36 @@ impl<K, V> FileInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
         return self;
     }
 
+    pub fn size(&self) -> Option<u64> {
+        return self.size;
+    }
+
+    pub fn set_size(&mut self, size: u64) -> &mut Self {
+        self.size = Some(size);
+        return self;
+    }
+
+    pub fn set_executable(&mut self, executable: bool) -> &mut Self {
+        self.executable = executable;
+        return self;
+    }
+
+    /// Whether the recorded mode marks this file read-only; `false` when no
+    /// mode was recorded
+    pub fn is_readonly(&self) -> bool {
+        return self.mode.is_some_and(mode_is_readonly);
+    }
+
+    pub fn set_mode(&mut self, mode: u32) -> &mut Self {
+        self.mode = Some(mode);
+        return self;
+    }
+
+    pub fn set_git_status(&mut self, git_status: GitStatus) -> &mut Self {
+        self.git_status = Some(git_status);
+        return self;
+    }
+
     /// let dir_info = DirInfo::new(/*...*/);
     /// let time_now = std::time::SystemTime::now();
     ///
@@ -271,6 +1030,8 @@ impl<K, V> FileInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
     /// // Returns Some(Greater) if time_now is greater than dir_info.time
     /// // Returns `None` if `dir_info` does not have a set `last_modified`
     /// let compared_time = dir_info.cmp(time_now);
+    // Compares against a time rather than another node, so it isn't Ord::cmp
+    #[allow(clippy::should_implement_trait)]
     pub fn cmp(&self, time: &SystemTime) -> Option<Ordering> {
         match self.last_modified {
             Some(last_time) => return Some(last_time.cmp(time)),
@@ -283,7 +1044,23 @@ impl<K, V> FileInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
             Some(map) => { map.insert(key, value); },
             None => self.fields = Some(map!(key : value)),
         }
-        return self.into();
+        return self;
+    }
+
+    pub fn get_field(&self, key: &K) -> Option<&V> {
+        return self.fields.as_ref().and_then(|map| map.get(key));
+    }
+
+    pub fn contains_field(&self, key: &K) -> bool {
+        return self.fields.as_ref().is_some_and(|map| map.contains_key(key));
+    }
+
+    /// Removes `key`, resetting `fields` to `None` once the map is empty
+    pub fn remove_field(&mut self, key: &K) -> Option<V> {
+        let map = self.fields.as_mut()?;
+        let value = map.remove(key);
+        if map.is_empty() { self.fields = None; }
+        return value;
     }
 
     pub fn build(&self) -> Self {
@@ -297,29 +1074,248 @@ impl<K, V> Clone for FileInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
             name: s!(self.name),
             path: self.path.clone(),
             last_modified: self.last_modified,
+            size: self.size,
+            executable: self.executable,
+            mode: self.mode,
+            git_status: self.git_status,
+            hash: self.hash.clone(),
             fields: self.fields.clone(),
         }
     }
 }
 
+/// A symlink that wasn't followed during the walk
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SymlinkInfo {
+    pub name: String,
+    pub path: PathBuf,
+    /// Where the link points, resolved against the link's directory when
+    /// relative. The target may not exist.
+    pub target: PathBuf,
+    /// Of the link itself
+    pub last_modified: Option<SystemTime>,
+}
+
+/// What DirInfo::sort() orders content by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    /// Oldest first, with unknown times last
+    Modified,
+    /// Smallest first, using total_size() for directories and putting
+    /// unknown file sizes last
+    Size,
+}
+
+impl SortKey {
+    fn compare<K: Hash + Eq + Clone, V: Clone>(&self, a: &FsNode<K, V>, b: &FsNode<K, V>) -> Ordering {
+        match self {
+            SortKey::Name => a.name_ref().cmp(b.name_ref()),
+            SortKey::Modified => none_last(a.last_modified(), b.last_modified()),
+            SortKey::Size => {
+                let size = |node: &FsNode<K, V>| match node {
+                    FsNode::Directory(d) => Some(d.total_size()),
+                    FsNode::File(f) => f.size,
+                    FsNode::Symlink(_) => None,
+                };
+                none_last(size(a), size(b))
+            },
+        }
+    }
+}
+
+//No write bit for anyone on Unix, FILE_ATTRIBUTE_READONLY on Windows
+#[allow(unused_variables)]
+fn mode_is_readonly(mode: u32) -> bool {
+    #[cfg(unix)]
+    {
+        return mode & 0o222 == 0;
+    }
+    #[cfg(target_os = "windows")]
+    {
+        return mode & 0x1 != 0;
+    }
+    #[cfg(not(any(unix, target_os = "windows")))]
+    {
+        return false;
+    }
+}
+
+fn none_last<T: Ord>(a: Option<T>, b: Option<T>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Rendering options for DirInfo::build_tree_with()
+#[derive(Debug, Clone)]
+pub struct TreeConfig {
+    /// Draw the vertical guide only on every Nth level, starting with the
+    /// first; 1 draws every guide
+    pub guide_every: usize,
+    /// Style names and arrows with ANSI color codes
+    pub color: bool,
+    /// Glyphs used for guides and arrows
+    pub style: TreeStyle,
+    /// Where files go relative to directories
+    pub order: SortOrder,
+    /// Levels to render below the root; deeper content is hidden and its
+    /// directory marked with an ellipsis
+    pub max_depth: Option<usize>,
+    /// Append each file's size and each directory's total_size()
+    pub sizes: bool,
+}
+
+/// Order of entries within each directory of a rendered tree. Entries are
+/// sorted by name within each group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    #[default]
+    FilesFirst,
+    DirsFirst,
+    /// Purely by name, regardless of type
+    Mixed,
+}
+
+/// Glyphs drawn by build_tree_with()
+#[derive(Debug, Clone)]
+pub struct TreeStyle {
+    pub joint: String,
+    pub node: String,
+    pub vertical: String,
+    pub horizontal: String,
+    pub expanded: String,
+    pub collapsed: String,
+    /// Marks directories whose content was cut off by max_depth
+    pub ellipsis: String,
+}
+
+impl Default for TreeStyle {
+    fn default() -> Self {
+        return Self::unicode();
+    }
+}
+
+impl TreeStyle {
+    /// Box-drawing glyphs, the default look
+    pub fn unicode() -> Self {
+        Self {
+            joint: s!(Utf8::JointPipeSlim),
+            node: s!(Utf8::NodePipeCurved),
+            vertical: s!(Utf8::VPipeSlim),
+            horizontal: s!(Utf8::HPipeSlim),
+            expanded: s!(Utf8::ModLetterDownArrowhead),
+            collapsed: s!(Utf8::ModLetterRightArrowhead),
+            ellipsis: s!("…"),
+        }
+    }
+
+    /// Pure ASCII glyphs for consoles and logs without Unicode support
+    pub fn ascii() -> Self {
+        Self {
+            joint: s!("|"),
+            node: s!("`"),
+            vertical: s!("|"),
+            horizontal: s!("-"),
+            expanded: s!("v"),
+            collapsed: s!(">"),
+            ellipsis: s!("..."),
+        }
+    }
+
+    fn arrow(&self, expanded: bool) -> &str {
+        return if expanded { &self.expanded } else { &self.collapsed };
+    }
+}
+
+impl Default for TreeConfig {
+    fn default() -> Self {
+        Self {
+            guide_every: 1,
+            color: true,
+            style: TreeStyle::unicode(),
+            order: SortOrder::FilesFirst,
+            max_depth: None,
+            sizes: false,
+        }
+    }
+}
+
+impl TreeConfig {
+    fn draws_guide(&self, depth: usize) -> bool {
+        return self.guide_every <= 1 || (depth - 1).is_multiple_of(self.guide_every);
+    }
+}
+
+
+/// Part of a rendered tree line, styled by the output it is drawn into
+enum Segment {
+    /// Guides, brackets, links and sizes
+    Plain(String),
+    Arrow(String),
+    DirName(String),
+    FileName(String, Option<GitStatus>),
+}
+
+impl Segment {
+    fn text(&self) -> &str {
+        return match self {
+            Segment::Plain(text) | Segment::Arrow(text)
+            | Segment::DirName(text) | Segment::FileName(text, _) => text,
+        };
+    }
+
+    /// The text, wrapped in ANSI color codes when `color` is set
+    fn ansi(&self, color: bool) -> String {
+        if !color { return s!(self.text()); }
+        return match self {
+            Segment::Plain(text) => s!(text),
+            Segment::Arrow(arrow) => style!(Bold, FGGreen => arrow), // ˅ ˃
+            Segment::DirName(name) => style!(Bold, FGBlue => name),
+            Segment::FileName(name, Some(GitStatus::Modified)) => style!(FGYellow => name),
+            Segment::FileName(name, Some(GitStatus::Added)) => style!(FGGreen => name),
+            Segment::FileName(name, Some(GitStatus::Untracked)) => style!(FGRed => name),
+            Segment::FileName(name, Some(GitStatus::Clean) | None) => s!(name),
+        };
+    }
+
+    /// ansi() as a ratatui span with the same colors
+    #[cfg(feature = "ratatui")]
+    fn span(self, color: bool) -> Span<'static> {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let style = match &self {
+            _ if !color => Style::default(),
+            Segment::Arrow(_) => bold.fg(Color::Green),
+            Segment::DirName(_) => bold.fg(Color::Blue),
+            Segment::FileName(_, Some(GitStatus::Modified)) => Style::default().fg(Color::Yellow),
+            Segment::FileName(_, Some(GitStatus::Added)) => Style::default().fg(Color::Green),
+            Segment::FileName(_, Some(GitStatus::Untracked)) => Style::default().fg(Color::Red),
+            Segment::Plain(_) | Segment::FileName(_, Some(GitStatus::Clean) | None) => Style::default(),
+        };
+        return match self {
+            Segment::Plain(text) | Segment::Arrow(text)
+            | Segment::DirName(text) | Segment::FileName(text, _) => Span::styled(text, style),
+        };
+    }
+}
+
+/// A tree line and the size to show at its end
+type TreeLine = (Vec<Segment>, Option<u64>);
 
 fn tree_recursion<K: Hash + Eq + Clone, V: Clone>(
-    dir_info: &DirInfo<K, V>, path: String, tree: &mut Vec<String>
+    dir_info: &DirInfo<K, V>, path: String, tree: &mut Vec<TreeLine>,
+    config: &TreeConfig, depth: usize
 ) {
-    //Force files first
-    //TODO: make a config choice if folders or files first
-    let (mut contents, other_content): (Vec<_>, Vec<_>) = dir_info
-        .content
-        .iter()
-        .partition(|n| matches!(n, FsNode::File(_)));
-    contents.extend(other_content);
+    let contents = ordered(&dir_info.content, config.order);
 
     //Set up the formatted values
-    let joint = format!(" {}{}", 
-        Utf8::JointPipeSlim, Utf8::HPipeSlim.repeat(2));
-    let node = format!(" {}{}", 
-        Utf8::NodePipeCurved, Utf8::HPipeSlim.repeat(2));
-    let vline = format!(" {}  ", Utf8::VPipeSlim);
+    let style = &config.style;
+    let joint = format!(" {}{}", style.joint, style.horizontal.repeat(2));
+    let node = format!(" {}{}", style.node, style.horizontal.repeat(2));
+    let vline = format!(" {}  ", style.vertical);
 
     //Iterate through contents and add them to the tree
     let contents_len = contents.len();
@@ -330,26 +1326,1147 @@ fn tree_recursion<K: Hash + Eq + Clone, V: Clone>(
         let prefix = format!("{}{}", path, if is_last { &node } else { &joint });
 
         match entity {
-            FsNode::File(file) => tree.push(prefix.clone() + " " + &file.name),
+            FsNode::File(file) => tree.push((
+                vec![Segment::Plain(prefix + " "), Segment::FileName(s!(file.name), file.git_status)],
+                file.size.filter(|_| config.sizes),
+            )),
+            FsNode::Symlink(link) => tree.push((vec![Segment::Plain(prefix + " " + &link_label(link))], None)),
             FsNode::Directory(subdir) => {
-                tree.push(s!(
-                    prefix.clone(),
-                    "[", 
-                    style!(Bold, FGGreen => 
-                        Utf8::ModLetterDownArrowhead), // ˅ ˃
-                    "]",
-                    style!(Bold, FGBlue => subdir.name),
-                ));
+                let truncated = config.max_depth.is_some_and(|max| depth >= max);
+                let size = config.sizes.then(|| subdir.total_size());
+                tree.push((dir_segments(prefix, subdir, config, truncated), size));
 
                 //Recursively process expanded directories
-                let sub_path = if is_last {
+                if !subdir.expanded || truncated { continue; }
+                let sub_path = if is_last || !config.draws_guide(depth) {
                     path.clone() + "    "
                 } else {
                     path.clone() + &vline
                 };
-                tree_recursion(subdir, sub_path, tree);
+                tree_recursion(subdir, sub_path, tree, config, depth + 1);
+            }
+        }
+    }
+}
+
+fn expanded_default() -> bool {
+    return true;
+}
+
+//Collects (relative path, content hash) pairs, with `None` for directories
+#[cfg(feature = "content-hash")]
+fn checksum_recursion<K: Hash + Eq + Clone, V: Clone>(
+    dir_info: &DirInfo<K, V>, root: &Path, entries: &mut Vec<(String, Option<blake3::Hash>)>
+) -> io::Result<()> {
+    for node in dir_info.content.iter() {
+        let path = node.path();
+        let relative = s!(path.strip_prefix(root).unwrap_or(&path).display());
+        match node {
+            FsNode::Directory(d) => {
+                entries.push((relative, None));
+                checksum_recursion(d, root, entries)?;
+            },
+            FsNode::File(_) => entries.push((relative, Some(file_checksum(&path)?))),
+            FsNode::Symlink(l) => entries.push((relative + " -> " + &s!(l.target.display()), None)),
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(feature = "content-hash")]
+fn file_checksum(path: &Path) -> io::Result<blake3::Hash> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher)?;
+    return Ok(hasher.finalize());
+}
+
+//Only the snapshot at the diffed root is skipped; one further down is a
+//user's file like any other
+fn diff_recursion<K: Hash + Eq + Clone, V: Clone>(
+    older: &DirInfo<K, V>, newer: &DirInfo<K, V>, snapshot: &Path, changes: &mut Vec<Change>
+) {
+    let is_snapshot = |node: &FsNode<K, V>| 
+        matches!(node, FsNode::File(f) if f.path == snapshot);
+
+    let previous: HashMap<&Path, &FsNode<K, V>> = older.content.iter()
+        .filter(|node| !is_snapshot(node))
+        .map(|node| (node.path_ref(), node))
+        .collect();
+
+    for node in newer.content.iter().filter(|node| !is_snapshot(node)) {
+        match (previous.get(node.path_ref()), node) {
+            (None, _) => changes.push(Change::Added(node.path())),
+            (Some(FsNode::Directory(old)), FsNode::Directory(new)) => 
+                diff_recursion(old, new, snapshot, changes),
+            (Some(FsNode::File(old)), FsNode::File(new)) => {
+                if old.last_modified != new.last_modified {
+                    changes.push(Change::Modified(new.path.clone()));
+                }
+            },
+            (Some(FsNode::Symlink(old)), FsNode::Symlink(new)) => {
+                if old.target != new.target || old.last_modified != new.last_modified {
+                    changes.push(Change::Modified(new.path.clone()));
+                }
+            },
+            //Changed between kinds of node
+            (Some(_), _) => {
+                changes.push(Change::Removed(node.path()));
+                changes.push(Change::Added(node.path()));
+            },
+        }
+    }
+
+    let current: Vec<&Path> = newer.content.iter().map(|n| n.path_ref()).collect();
+    for node in older.content.iter().filter(|node| !is_snapshot(node)) {
+        if !current.contains(&node.path_ref()) {
+            changes.push(Change::Removed(node.path()));
+        }
+    }
+}
+
+fn patch_recursion<K, V>(
+    prev: &DirInfo<K, V>, next: &DirInfo<K, V>, ops: &mut Vec<serde_json::Value>
+) where K: Hash + Eq + Clone + Serialize, V: Clone + Serialize {
+    if to_json(&prev.empty_clone()) != to_json(&next.empty_clone()) {
+        ops.push(patch_op("replace", &next.path, Some(to_json(&next.empty_clone()))));
+    }
+
+    let previous: HashMap<&Path, &FsNode<K, V>> = prev.content.iter()
+        .map(|node| (node.path_ref(), node))
+        .collect();
+
+    for node in next.content.iter() {
+        match (previous.get(node.path_ref()), node) {
+            (None, _) => ops.push(patch_op("add", node.path_ref(), Some(to_json(node)))),
+            (Some(FsNode::Directory(old)), FsNode::Directory(new)) => 
+                patch_recursion(old, new, ops),
+            (Some(old @ FsNode::File(_)), FsNode::File(_))
+            | (Some(old @ FsNode::Symlink(_)), FsNode::Symlink(_)) => {
+                if to_json(old) != to_json(node) {
+                    ops.push(patch_op("replace", node.path_ref(), Some(to_json(node))));
+                }
+            },
+            //Changed between kinds of node
+            (Some(_), _) => ops.push(patch_op("replace", node.path_ref(), Some(to_json(node)))),
+        }
+    }
+
+    let current: Vec<&Path> = next.content.iter().map(|n| n.path_ref()).collect();
+    for node in prev.content.iter() {
+        if !current.contains(&node.path_ref()) {
+            ops.push(patch_op("remove", node.path_ref(), None));
+        }
+    }
+}
+
+fn patch_op(op: &str, path: &Path, value: Option<serde_json::Value>) -> serde_json::Value {
+    let mut patch = serde_json::Map::new();
+    patch.insert(s!("op"), serde_json::Value::from(op));
+    patch.insert(s!("path"), serde_json::Value::from(s!(path.display())));
+    if let Some(value) = value { patch.insert(s!("value"), value); }
+    return serde_json::Value::Object(patch);
+}
+
+fn to_json<T: Serialize>(value: &T) -> serde_json::Value {
+    return serde_json::to_value(value).unwrap_or_default();
+}
+
+//Bracketed arrow and name of a directory line, marked with an ellipsis when
+//`truncated` hides content it has
+fn dir_segments<K: Hash + Eq + Clone, V: Clone>(
+    prefix: String, dir_info: &DirInfo<K, V>, config: &TreeConfig, truncated: bool
+) -> Vec<Segment> {
+    let mut segments = vec![
+        Segment::Plain(prefix + "["),
+        Segment::Arrow(s!(config.style.arrow(dir_info.expanded))),
+        Segment::Plain(s!("]")),
+        Segment::DirName(s!(dir_info.name)),
+    ];
+    if truncated && dir_info.expanded && !dir_info.content.is_empty() {
+        segments.push(Segment::Plain(s!(" ", config.style.ellipsis)));
+    }
+    return segments;
+}
+
+fn ordered<K: Hash + Eq + Clone, V: Clone>(
+    content: &[FsNode<K, V>], order: SortOrder
+) -> Vec<&FsNode<K, V>> {
+    let mut contents: Vec<&FsNode<K, V>> = content.iter().collect();
+    contents.sort_by(|a, b| a.name_ref().cmp(b.name_ref()));
+    //Stable, so names stay sorted within each group
+    match order {
+        SortOrder::FilesFirst => contents.sort_by_key(|n| n.is_dir()),
+        SortOrder::DirsFirst => contents.sort_by_key(|n| !n.is_dir()),
+        SortOrder::Mixed => {},
+    }
+    return contents;
+}
+
+//Appends the sizes in a right-aligned column past the widest line
+fn align_sizes(tree: Vec<TreeLine>) -> Vec<Vec<Segment>> {
+    let width = |segments: &[Segment]| -> usize {
+        segments.iter().map(|segment| segment.text().chars().count()).sum()
+    };
+    let widest = tree.iter().map(|(segments, _)| width(segments)).max().unwrap_or(0);
+
+    return tree.into_iter().map(|(mut segments, size)| {
+        if let Some(size) = size {
+            let padding = " ".repeat(widest - width(&segments));
+            segments.push(Segment::Plain(format!("{}  {:>10}", padding, format_bytes(size))));
+        }
+        segments
+    }).collect();
+}
+
+/// `theirs` laid over `ours`, `None` only when both are
+fn merge_fields<K: Hash + Eq, V>(
+    ours: Option<HashMap<K, V>>, theirs: Option<HashMap<K, V>>
+) -> Option<HashMap<K, V>> {
+    return match (ours, theirs) {
+        (Some(mut ours), Some(theirs)) => {
+            ours.extend(theirs);
+            Some(ours)
+        },
+        (ours, theirs) => theirs.or(ours),
+    };
+}
+
+fn html_recursion<K: Hash + Eq + Clone, V: Clone>(dir_info: &DirInfo<K, V>, html: &mut String) {
+    html.push_str(&format!(
+        "<li class=\"dir\" data-expanded=\"{}\">{}<ul>", dir_info.expanded, escape_html(&dir_info.name)
+    ));
+    for node in ordered(&dir_info.content, SortOrder::FilesFirst) {
+        match node {
+            FsNode::Directory(d) => html_recursion(d, html),
+            FsNode::File(f) => html.push_str(&format!("<li class=\"file\">{}</li>", escape_html(&f.name))),
+            FsNode::Symlink(l) => html.push_str(&format!(
+                "<li class=\"symlink\">{}</li>", escape_html(&link_label(l))
+            )),
+        }
+    }
+    html.push_str("</ul></li>");
+}
+
+fn markdown_recursion<K: Hash + Eq + Clone, V: Clone>(
+    dir_info: &DirInfo<K, V>, depth: usize, markdown: &mut String
+) {
+    markdown.push_str(&format!("{}- **{}**\n", "  ".repeat(depth), escape_markdown(&dir_info.name)));
+    for node in ordered(&dir_info.content, SortOrder::FilesFirst) {
+        let indent = "  ".repeat(depth + 1);
+        match node {
+            FsNode::Directory(d) => markdown_recursion(d, depth + 1, markdown),
+            FsNode::File(f) => markdown.push_str(&format!("{}- {}\n", indent, escape_markdown(&f.name))),
+            FsNode::Symlink(l) => markdown.push_str(&format!(
+                "{}- {}\n", indent, escape_markdown(&link_label(l))
+            )),
+        }
+    }
+}
+
+fn dot_recursion<K: Hash + Eq + Clone, V: Clone>(dir_info: &DirInfo<K, V>, dot: &mut String) {
+    let parent = escape_dot(&s!(dir_info.path.display()));
+    for node in ordered(&dir_info.content, SortOrder::FilesFirst) {
+        let id = escape_dot(&s!(node.path_ref().display()));
+        let (label, shape) = match node {
+            FsNode::Directory(d) => (escape_dot(&d.name), "folder"),
+            FsNode::File(f) => (escape_dot(&f.name), "box"),
+            FsNode::Symlink(l) => (escape_dot(&link_label(l)), "box"),
+        };
+        dot.push_str(&format!("  \"{}\" [label=\"{}\", shape={}];\n", id, label, shape));
+        dot.push_str(&format!("  \"{}\" -> \"{}\";\n", parent, id));
+        if let FsNode::Directory(d) = node { dot_recursion(d, dot); }
+    }
+}
+
+fn link_label(link: &SymlinkInfo) -> String {
+    return s!(link.name, " -> ", link.target.display());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::Watcher;
+
+    fn walked(dir: &Path) -> DirInfo<N, N> {
+        let mut watcher: Watcher<N, N> = Watcher::new(dir.to_str().unwrap()).unwrap();
+        watcher.walk().unwrap();
+        return watcher.dir_info;
+    }
+
+    /// A directory at `path` holding `content`, built without touching disk
+    fn dir(path: &str, content: Vec<FsNode<N, N>>) -> DirInfo<N, N> {
+        let path = PathBuf::from(path);
+        return DirInfo {
+            name: s!(path.file_name().unwrap().to_string_lossy()),
+            path, last_modified: None, expanded: true, mode: None, content, fields: None,
+        };
+    }
+
+    fn file(path: &str, size: Option<u64>) -> FsNode<N, N> {
+        let path = PathBuf::from(path);
+        let mut file = FileInfo::new(s!(path.file_name().unwrap().to_string_lossy()), path, None, None);
+        file.size = size;
+        return FsNode::File(file);
+    }
+
+    #[cfg(feature = "content-hash")]
+    #[test]
+    fn content_checksum_compares_trees_by_content() {
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        for dir in [a.path(), b.path()] {
+            std::fs::create_dir(dir.join("sub")).unwrap();
+            std::fs::write(dir.join("sub").join("file"), "same bytes").unwrap();
+        }
+        let checksum = walked(a.path()).content_checksum().unwrap();
+        assert_eq!(checksum, walked(b.path()).content_checksum().unwrap());
+
+        // Same size and name, one byte apart
+        std::fs::write(b.path().join("sub").join("file"), "same bytez").unwrap();
+        assert_ne!(checksum, walked(b.path()).content_checksum().unwrap());
+    }
+
+    #[test]
+    fn json_without_later_fields_loads_with_defaults() {
+        let json = r#"{
+            "name": "root", "path": "/root", "last_modified": null, "fields": null,
+            "content": [{"File": {"name": "a", "path": "/root/a", "last_modified": null, "fields": null}}]
+        }"#;
+        let dir: DirInfo<N, N> = serde_json::from_str(json).unwrap();
+        assert!(dir.expanded);
+        assert_eq!(dir.mode, None);
+        let Some(FsNode::File(file)) = dir.content.first() else { panic!() };
+        assert_eq!((file.size, file.executable, file.mode), (None, false, None));
+    }
+
+    #[test]
+    fn get_mut_changes_a_nested_dir_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("outer").join("inner");
+        std::fs::create_dir_all(&nested).unwrap();
+        let mut tree = walked(dir.path());
+
+        let Some(FsNode::Directory(inner)) = tree.get_mut(&nested) else { panic!() };
+        assert!(inner.expanded);
+        inner.expanded = false;
+
+        let Some(FsNode::Directory(inner)) = tree.find(&nested) else { panic!() };
+        assert!(!inner.expanded);
+        assert!(tree.get_mut(&dir.path().join("missing")).is_none());
+    }
+
+    #[cfg(feature = "ratatui")]
+    #[test]
+    fn tree_lines_follow_the_tree_config() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("b_dir").join("deep")).unwrap();
+        std::fs::write(dir.path().join("a_file"), "12345").unwrap();
+        std::fs::write(dir.path().join("b_dir").join("deep").join("hidden"), "").unwrap();
+        let tree = walked(dir.path());
+
+        let config = TreeConfig {
+            color: false,
+            style: TreeStyle::ascii(),
+            order: SortOrder::DirsFirst,
+            max_depth: Some(2),
+            sizes: true,
+            ..TreeConfig::default()
+        };
+        let text: Vec<String> = tree.build_tree_lines_with(&config).iter()
+            .map(|line| line.spans.iter().map(|span| span.content.as_ref()).collect())
+            .collect();
+        assert_eq!(text, tree.build_tree_with(&config));
+        assert!(text[1].contains("b_dir") && text[3].contains("a_file"), "{text:?}");
+        assert!(text.iter().all(|line| line.is_ascii()), "{text:?}");
+
+        let colored = tree.build_tree_lines_with(&TreeConfig::default());
+        assert_eq!(colored[0].spans[1].style.fg, Some(Color::Green));
+    }
+
+    #[test]
+    fn each_dir_arrow_follows_its_own_expanded_flag() {
+        let mut collapsed = dir("/t/collapsed", vec![file("/t/collapsed/x", None)]);
+        collapsed.set_expanded(false);
+        let root = dir("/t", vec![
+            FsNode::Directory(dir("/t/open", vec![])),
+            FsNode::Directory(collapsed),
+        ]);
+
+        let lines = root.build_tree_plain();
+        let line = |name: &str| lines.iter().find(|line| line.ends_with(name)).unwrap();
+        assert!(line("open").contains(&s!(Utf8::ModLetterDownArrowhead)), "{lines:?}");
+        assert!(line("collapsed").contains(&s!(Utf8::ModLetterRightArrowhead)), "{lines:?}");
+        assert!(!lines.iter().any(|line| line.ends_with("x")));
+    }
+
+    #[test]
+    fn total_size_sums_nested_files_and_empty_dirs_add_nothing() {
+        let root = dir("/s", vec![
+            file("/s/a", Some(100)),
+            file("/s/unknown", None),
+            FsNode::Directory(dir("/s/empty", vec![])),
+            FsNode::Directory(dir("/s/sub", vec![
+                file("/s/sub/b", Some(20)),
+                FsNode::Directory(dir("/s/sub/deeper", vec![file("/s/sub/deeper/c", Some(3))])),
+            ])),
+        ]);
+        assert_eq!(root.total_size(), 123);
+        let Some(FsNode::Directory(empty)) = root.find(Path::new("/s/empty")) else { panic!() };
+        assert_eq!(empty.total_size(), 0);
+        let Some(FsNode::Directory(sub)) = root.find(Path::new("/s/sub")) else { panic!() };
+        assert_eq!(sub.total_size(), 23);
+    }
+
+    #[test]
+    fn counts_files_and_dirs_once_each() {
+        assert_eq!(dir("/c", vec![]).counts(false), (0, 0));
+        assert_eq!(dir("/c", vec![]).counts(true), (0, 1));
+
+        let flat = dir("/c", vec![file("/c/a", None), file("/c/b", None), file("/c/c", None)]);
+        assert_eq!(flat.counts(false), (3, 0));
+
+        let nested = dir("/c", vec![
+            file("/c/a", None),
+            FsNode::Directory(dir("/c/x", vec![
+                file("/c/x/b", None),
+                FsNode::Directory(dir("/c/x/y", vec![])),
+            ])),
+        ]);
+        assert_eq!(nested.counts(false), (2, 2));
+        assert_eq!(nested.counts(true), (2, 3));
+    }
+
+    #[test]
+    fn iter_walks_depth_first_into_collapsed_dirs() {
+        let mut shut = dir("/i/b/shut", vec![file("/i/b/shut/inner", None)]);
+        shut.set_expanded(false);
+        let root = dir("/i", vec![
+            file("/i/a", None),
+            FsNode::Directory(dir("/i/b", vec![FsNode::Directory(shut), file("/i/b/c", None)])),
+            file("/i/d", None),
+        ]);
+
+        let order: Vec<(usize, String)> = root.iter_with_depth()
+            .map(|(depth, node)| (depth, node.name()))
+            .collect();
+        assert_eq!(order, [
+            (1, s!("a")), (1, s!("b")), (2, s!("shut")), (3, s!("inner")), (2, s!("c")), (1, s!("d")),
+        ]);
+        assert_eq!(root.iter().count(), 6);
+    }
+
+    #[test]
+    fn find_hits_nested_nodes_and_misses_the_rest() {
+        let mut root = dir("/f", vec![
+            file("/f/top", None),
+            FsNode::Directory(dir("/f/sub", vec![file("/f/sub/deep", Some(1))])),
+            // Shares "/f/sub" as a string prefix only
+            file("/f/subway", None),
+        ]);
+
+        assert_eq!(root.find(Path::new("/f/sub/deep")).map(|node| node.name()), Some(s!("deep")));
+        assert_eq!(root.find(Path::new("/f/subway")).map(|node| node.name()), Some(s!("subway")));
+        assert!(root.find(Path::new("/f/sub/missing")).is_none());
+        assert!(root.find(Path::new("/elsewhere")).is_none());
+        // The root is a DirInfo, not one of its own nodes
+        assert!(root.find(Path::new("/f")).is_none());
+
+        let Some(FsNode::File(deep)) = root.find_mut(Path::new("/f/sub/deep")) else { panic!() };
+        deep.set_size(2);
+        assert_eq!(root.total_size(), 2);
+        assert!(root.find_mut(Path::new("/f")).is_none());
+    }
+
+    #[test]
+    fn search_by_name_matches_at_every_level() {
+        let root = dir("/q", vec![
+            file("/q/Report.txt", None),
+            FsNode::Directory(dir("/q/reports", vec![
+                file("/q/reports/report-1", None),
+                FsNode::Directory(dir("/q/reports/old", vec![file("/q/reports/old/report-0", None)])),
+            ])),
+        ]);
+        let names = |nodes: Vec<&FsNode<N, N>>| nodes.iter().map(|node| node.name()).collect::<Vec<_>>();
+
+        assert_eq!(names(root.search_by_name("report")), ["reports", "report-1", "report-0"]);
+        assert_eq!(names(root.search_by_name_ci("REPORT")), ["Report.txt", "reports", "report-1", "report-0"]);
+        assert_eq!(root.search_by_name("").len(), root.iter().count());
+        assert!(root.search_by_name("missing").is_empty());
+    }
+
+    #[test]
+    fn filter_keeps_the_dirs_leading_to_matches() {
+        let root = dir("/p", vec![
+            file("/p/main.rs", None),
+            file("/p/notes.md", None),
+            FsNode::Directory(dir("/p/docs", vec![file("/p/docs/guide.md", None)])),
+            FsNode::Directory(dir("/p/src", vec![
+                FsNode::Directory(dir("/p/src/net", vec![
+                    file("/p/src/net/tcp.rs", None), file("/p/src/net/README", None),
+                ])),
+            ])),
+        ]);
+
+        let kept = root.filter(|file| file.path.extension().is_some_and(|ext| ext == "rs"));
+        assert_eq!(kept.flatten_all(), [
+            PathBuf::from("/p/main.rs"),
+            PathBuf::from("/p/src"),
+            PathBuf::from("/p/src/net"),
+            PathBuf::from("/p/src/net/tcp.rs"),
+        ]);
+        // The original is left alone
+        assert!(root.find(Path::new("/p/docs/guide.md")).is_some());
+    }
+
+    #[test]
+    fn diff_reports_added_removed_dirs_once_and_touched_files() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let stamped = |path: &str, secs| {
+            let FsNode::File(mut f) = file(path, None) else { unreachable!() };
+            f.last_modified = Some(at(secs));
+            FsNode::File(f)
+        };
+        let old = dir("/d", vec![
+            stamped("/d/same", 1),
+            stamped("/d/touched", 1),
+            FsNode::Directory(dir("/d/gone", vec![stamped("/d/gone/a", 1), stamped("/d/gone/b", 1)])),
+        ]);
+        let new = dir("/d", vec![
+            stamped("/d/same", 1),
+            stamped("/d/touched", 2),
+            stamped("/d/added", 2),
+        ]);
+
+        let mut changes = old.diff(&new);
+        changes.sort_by_key(|change| format!("{change:?}"));
+        assert_eq!(changes, [
+            Change::Added(PathBuf::from("/d/added")),
+            Change::Modified(PathBuf::from("/d/touched")),
+            Change::Removed(PathBuf::from("/d/gone")),
+        ]);
+        assert!(new.diff(&new).is_empty());
+    }
+
+    #[test]
+    fn plain_trees_have_no_escape_codes() {
+        let root = dir("/e", vec![
+            file("/e/a", None),
+            FsNode::Directory(dir("/e/sub", vec![file("/e/sub/b", None)])),
+        ]);
+        assert!(root.build_tree().iter().any(|line| line.contains("\x1b[")));
+
+        let plain = root.build_tree_plain();
+        assert_eq!(plain.len(), 4);
+        assert!(plain.iter().all(|line| !line.contains("\x1b[")), "{plain:?}");
+    }
+
+    #[test]
+    fn ascii_style_draws_only_ascii() {
+        let root = dir("/g", vec![
+            file("/g/a", None),
+            FsNode::Directory(dir("/g/sub", vec![
+                FsNode::Directory(dir("/g/sub/deeper", vec![file("/g/sub/deeper/b", None)])),
+                file("/g/sub/c", None),
+            ])),
+        ]);
+        let ascii = root.build_tree_styled(&TreeStyle::ascii());
+        assert!(ascii.iter().all(|line| line.is_ascii()), "{ascii:?}");
+        assert!(!root.build_tree_styled(&TreeStyle::unicode()).iter().all(|line| line.is_ascii()));
+    }
+
+    #[test]
+    fn sort_orders_group_or_mix_files_and_dirs_by_name() {
+        let root = dir("/o", vec![
+            FsNode::Directory(dir("/o/d_dir", vec![])),
+            file("/o/c_file", None),
+            FsNode::Directory(dir("/o/b_dir", vec![])),
+            file("/o/a_file", None),
+        ]);
+        let names = |order| -> Vec<String> {
+            let config = TreeConfig { order, color: false, style: TreeStyle::ascii(), ..TreeConfig::default() };
+            root.build_tree_with(&config).iter().skip(1)
+                .map(|line| s!(line.rsplit([' ', ']']).next().unwrap()))
+                .collect()
+        };
+
+        assert_eq!(names(SortOrder::FilesFirst), ["a_file", "c_file", "b_dir", "d_dir"]);
+        assert_eq!(names(SortOrder::DirsFirst), ["b_dir", "d_dir", "a_file", "c_file"]);
+        assert_eq!(names(SortOrder::Mixed), ["a_file", "b_dir", "c_file", "d_dir"]);
+    }
+
+    #[test]
+    fn build_tree_depth_stops_at_the_limit_and_flags_hidden_content() {
+        let root = dir("/l", vec![
+            file("/l/top", None),
+            FsNode::Directory(dir("/l/full", vec![file("/l/full/hidden", None)])),
+            FsNode::Directory(dir("/l/empty", vec![])),
+        ]);
+
+        let lines = root.build_tree_with(&TreeConfig { max_depth: Some(1), color: false, ..TreeConfig::default() });
+        assert_eq!(lines.len(), 4, "{lines:?}");
+        assert!(!lines.iter().any(|line| line.contains("hidden")));
+        assert!(lines.iter().any(|line| line.ends_with("full …")), "{lines:?}");
+        assert!(lines.iter().any(|line| line.ends_with("empty")), "{lines:?}");
+
+        let Some(FsNode::Directory(full)) = root.find(Path::new("/l/full")) else { panic!() };
+        assert!(full.expanded);
+        assert_eq!(root.build_tree_depth(2).len(), 5);
+    }
+
+    #[test]
+    fn sizes_line_up_after_files_and_dir_totals() {
+        let root = dir("/z", vec![
+            file("/z/small", Some(512)),
+            file("/z/unknown", None),
+            FsNode::Directory(dir("/z/nested", vec![file("/z/nested/big", Some(1536))])),
+        ]);
+
+        let lines = root.build_tree_with(&TreeConfig { sizes: true, color: false, ..TreeConfig::default() });
+        let line = |name: &str| lines.iter().find(|line| line.contains(name)).unwrap();
+        assert!(lines[0].ends_with("   2.0 KiB"), "{lines:?}");
+        assert!(line("small").ends_with("     512 B"), "{lines:?}");
+        assert!(line("nested").ends_with("   1.5 KiB"), "{lines:?}");
+        assert!(line("unknown").ends_with("unknown"), "{lines:?}");
+        // Every size ends in the same column
+        let sized: Vec<_> = lines.iter().filter(|line| line.ends_with('B')).collect();
+        assert_eq!(sized.len(), 4);
+        assert!(sized.iter().all(|line| line.chars().count() == sized[0].chars().count()));
+    }
+
+    #[test]
+    fn sort_orders_every_level_by_each_key() {
+        let at = |secs| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        let with = |path: &str, size, modified| {
+            let FsNode::File(mut f) = file(path, size) else { unreachable!() };
+            f.last_modified = modified;
+            FsNode::File(f)
+        };
+        let mut big = dir("/k/big", vec![with("/k/big/z", Some(1), at(5)), with("/k/big/a", Some(90), at(1))]);
+        big.last_modified = at(2);
+        let mut root = dir("/k", vec![
+            with("/k/c", Some(10), None),
+            FsNode::Directory(big),
+            with("/k/a", None, at(3)),
+            with("/k/b", Some(5), at(4)),
+        ]);
+        let names = |dir: &DirInfo<N, N>| dir.content.iter().map(|node| node.name()).collect::<Vec<_>>();
+
+        root.sort(SortKey::Name);
+        assert_eq!(names(&root), ["a", "b", "big", "c"]);
+        let Some(FsNode::Directory(big)) = root.find(Path::new("/k/big")) else { panic!() };
+        assert_eq!(names(big), ["a", "z"]);
+
+        root.sort(SortKey::Modified);
+        assert_eq!(names(&root), ["big", "a", "b", "c"]);
+
+        root.sort(SortKey::Size);
+        assert_eq!(names(&root), ["b", "c", "big", "a"]);
+        let Some(FsNode::Directory(big)) = root.find(Path::new("/k/big")) else { panic!() };
+        assert_eq!(names(big), ["z", "a"]);
+    }
+
+    #[test]
+    fn flatten_lists_each_file_once_in_content_order() {
+        let root = dir("/n", vec![
+            file("/n/b", None),
+            FsNode::Directory(dir("/n/x", vec![
+                FsNode::Directory(dir("/n/x/y", vec![file("/n/x/y/deep", None)])),
+                file("/n/x/c", None),
+            ])),
+            file("/n/a", None),
+        ]);
+
+        let files = root.flatten();
+        assert_eq!(files, ["/n/b", "/n/x/y/deep", "/n/x/c", "/n/a"].map(PathBuf::from));
+        assert_eq!(root.flatten(), files);
+        assert_eq!(root.flatten_all(), ["/n/b", "/n/x", "/n/x/y", "/n/x/y/deep", "/n/x/c", "/n/a"].map(PathBuf::from));
+    }
+
+    #[test]
+    fn prune_empty_drops_dirs_without_files_beneath() {
+        let mut root = dir("/e", vec![
+            FsNode::Directory(dir("/e/empty", vec![])),
+            FsNode::Directory(dir("/e/nested_empty", vec![
+                FsNode::Directory(dir("/e/nested_empty/also", vec![
+                    FsNode::Directory(dir("/e/nested_empty/also/deep", vec![])),
+                ])),
+            ])),
+            FsNode::Directory(dir("/e/full", vec![
+                FsNode::Directory(dir("/e/full/hollow", vec![])),
+                FsNode::Directory(dir("/e/full/sub", vec![file("/e/full/sub/f", None)])),
+            ])),
+        ]);
+
+        root.prune_empty();
+        assert_eq!(root.flatten_all(), ["/e/full", "/e/full/sub", "/e/full/sub/f"].map(PathBuf::from));
+    }
+
+    #[test]
+    fn fields_read_back_what_was_added() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut info: DirInfo<String, u32> = DirInfo::from(dir.path().to_str().unwrap()).unwrap();
+        let mut file: FileInfo<String, u32> = FileInfo::new(s!("f"), dir.path().join("f"), None, None);
+        assert_eq!(info.get_field(&s!("owner")), None);
+        assert!(!file.contains_field(&s!("lines")));
+
+        info.add_field(s!("owner"), 7);
+        file.add_field(s!("lines"), 42).add_field(s!("words"), 300);
+        assert_eq!(info.get_field(&s!("owner")), Some(&7));
+        assert!(info.contains_field(&s!("owner")));
+        assert_eq!(file.get_field(&s!("lines")), Some(&42));
+        assert_eq!(file.get_field(&s!("missing")), None);
+        assert!(!file.contains_field(&s!("missing")));
+    }
+
+    #[test]
+    fn removing_the_last_field_resets_the_map() {
+        let mut file: FileInfo<&str, u32> = FileInfo::new(s!("f"), PathBuf::from("/r/f"), None, None);
+        assert_eq!(file.remove_field(&"missing"), None);
+
+        file.add_field("a", 1).add_field("b", 2);
+        assert_eq!(file.remove_field(&"a"), Some(1));
+        assert_eq!(file.remove_field(&"missing"), None);
+        assert!(file.fields.is_some());
+        assert_eq!(file.remove_field(&"b"), Some(2));
+        assert_eq!(file.fields, None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut info: DirInfo<&str, u32> = DirInfo::from(dir.path().to_str().unwrap()).unwrap();
+        info.add_field("only", 3);
+        assert_eq!(info.remove_field(&"only"), Some(3));
+        assert_eq!(info.fields, None);
+    }
+
+    #[test]
+    fn merge_unions_by_path_and_takes_the_newer_file() {
+        let at = |secs| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        let stamped = |path: &str, secs| {
+            let FsNode::File(mut f) = file(path, Some(secs)) else { unreachable!() };
+            f.last_modified = at(secs);
+            FsNode::File(f)
+        };
+
+        // Disjoint
+        let mut ours = dir("/m", vec![stamped("/m/a", 1)]);
+        ours.merge(dir("/m", vec![stamped("/m/b", 1)]));
+        assert_eq!(ours.flatten_all(), ["/m/a", "/m/b"].map(PathBuf::from));
+
+        // Overlapping, and nested directories merged rather than replaced
+        let mut ours = dir("/m", vec![
+            stamped("/m/shared", 1),
+            FsNode::Directory(dir("/m/sub", vec![stamped("/m/sub/ours", 1)])),
+        ]);
+        ours.merge(dir("/m", vec![
+            stamped("/m/shared", 9),
+            FsNode::Directory(dir("/m/sub", vec![stamped("/m/sub/theirs", 1)])),
+        ]));
+        assert_eq!(ours.flatten_all(), ["/m/shared", "/m/sub", "/m/sub/ours", "/m/sub/theirs"].map(PathBuf::from));
+        let Some(FsNode::File(shared)) = ours.find(Path::new("/m/shared")) else { panic!() };
+        assert_eq!((shared.last_modified, shared.size), (at(9), Some(9)));
+    }
+
+    #[test]
+    fn trees_compare_equal_after_a_bincode_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub").join("file"), "data").unwrap();
+        let mut watcher: Watcher<String, u32> = Watcher::new(dir.path().to_str().unwrap()).unwrap();
+        watcher.walk().unwrap();
+        let mut tree = watcher.dir_info;
+        tree.add_field(s!("a"), 1).add_field(s!("b"), 2);
+
+        let bytes = bincode::serialize(&tree).unwrap();
+        let loaded: DirInfo<String, u32> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(loaded, tree);
+
+        // Field maps compare by content, not insertion order
+        let mut reordered = tree.clone();
+        reordered.set_fields(None).add_field(s!("b"), 2).add_field(s!("a"), 1);
+        assert_eq!(reordered, tree);
+        reordered.add_field(s!("a"), 3);
+        assert_ne!(reordered, tree);
+    }
+
+    #[test]
+    fn html_escapes_names_and_nests_lists() {
+        let mut shut = dir("/h/sub", vec![file("/h/sub/inner", None)]);
+        shut.set_expanded(false);
+        let root = dir("/h", vec![file("/h/<script>", None), FsNode::Directory(shut)]);
+
+        let html = root.to_html();
+        assert!(html.contains("<li class=\"file\">&lt;script&gt;</li>"), "{html}");
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("<li class=\"dir\" data-expanded=\"false\">sub<ul>"), "{html}");
+
+        // Every <ul>/<li> is closed in the order it was opened
+        let mut open = vec![];
+        for tag in html.split('<').skip(1).map(|rest| rest.split(['>', ' ']).next().unwrap()) {
+            match tag.strip_prefix('/') {
+                Some(closing) => assert_eq!(open.pop(), Some(closing), "{html}"),
+                None => open.push(tag),
             }
         }
+        assert!(open.is_empty(), "{html}");
+    }
+
+    #[test]
+    fn markdown_indents_two_spaces_per_level() {
+        let root = dir("/md", vec![
+            FsNode::Directory(dir("/md/sub_dir", vec![
+                FsNode::Directory(dir("/md/sub_dir/deep", vec![file("/md/sub_dir/deep/leaf", None)])),
+            ])),
+            file("/md/top*.md", None),
+        ]);
+
+        assert_eq!(root.to_markdown(), concat!(
+            "- **md**\n",
+            "  - top\\*.md\n",
+            "  - **sub\\_dir**\n",
+            "    - **deep**\n",
+            "      - leaf\n",
+        ));
+        assert!(!root.to_markdown().contains('\x1b'));
+    }
+
+    #[test]
+    fn dot_is_a_digraph_with_an_edge_per_child() {
+        let root = dir("/gv", vec![
+            file("/gv/say \"hi\"", None),
+            FsNode::Directory(dir("/gv/sub", vec![file("/gv/sub/leaf", None)])),
+        ]);
+
+        let dot = root.to_dot();
+        assert!(dot.starts_with("digraph tree {\n") && dot.ends_with("}\n"), "{dot}");
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains(" -> ")).collect();
+        assert_eq!(edges, [
+            r#"  "/gv" -> "/gv/say \"hi\"";"#,
+            r#"  "/gv" -> "/gv/sub";"#,
+            r#"  "/gv/sub" -> "/gv/sub/leaf";"#,
+        ]);
+        assert!(dot.contains(r#""/gv/sub" [label="sub", shape=folder];"#), "{dot}");
+        assert!(dot.contains(r#""/gv/sub/leaf" [label="leaf", shape=box];"#), "{dot}");
+    }
+
+    #[test]
+    fn depth_of_counts_levels_below_the_root() {
+        let root = dir("/dp", vec![
+            file("/dp/one", None),
+            FsNode::Directory(dir("/dp/a", vec![
+                FsNode::Directory(dir("/dp/a/b", vec![file("/dp/a/b/three", None)])),
+            ])),
+        ]);
+
+        assert_eq!(root.depth_of(Path::new("/dp")), Some(0));
+        assert_eq!(root.depth_of(Path::new("/dp/one")), Some(1));
+        assert_eq!(root.depth_of(Path::new("/dp/a/b")), Some(2));
+        assert_eq!(root.depth_of(Path::new("/dp/a/b/three")), Some(3));
+        assert_eq!(root.depth_of(Path::new("/dp/a/missing")), None);
+        // Outside the root, even where the text shares its prefix
+        assert_eq!(root.depth_of(Path::new("/elsewhere/one")), None);
+        assert_eq!(root.depth_of(Path::new("/dpx/one")), None);
+        assert_eq!(root.depth_of(Path::new("/")), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn apply_event_handles_each_kind_and_ignores_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        std::fs::create_dir(&sub).unwrap();
+        std::fs::write(sub.join("old"), "").unwrap();
+        std::fs::write(dir.path().join("gone"), "").unwrap();
+        let mut tree = walked(dir.path());
+        let event = |event, path: PathBuf, from: Option<PathBuf>| FsEvent {
+            wd: 1, event, name: s!(path.file_name().unwrap().to_string_lossy()), path, from,
+            time: SystemTime::now(),
+        };
+
+        std::fs::write(sub.join("new"), "").unwrap();
+        assert!(tree.apply_event(&event(Event::Create, sub.join("new"), None)));
+        assert!(tree.find(&sub.join("new")).is_some());
+
+        assert!(tree.apply_event(&event(Event::Delete, dir.path().join("gone"), None)));
+        assert!(tree.find(&dir.path().join("gone")).is_none());
+
+        std::fs::write(sub.join("old"), "grown").unwrap();
+        assert!(tree.apply_event(&event(Event::Modify, sub.join("old"), None)));
+        let Some(FsNode::File(old)) = tree.find(&sub.join("old")) else { panic!() };
+        assert_eq!(old.size, Some(5));
+
+        let moved = dir.path().join("moved");
+        assert!(tree.apply_event(&event(Event::Renamed, moved.clone(), Some(sub.join("old")))));
+        assert!(tree.find(&sub.join("old")).is_none());
+        assert!(matches!(tree.find(&moved), Some(FsNode::File(f)) if f.size == Some(5)));
+
+        assert!(tree.apply_event(&event(Event::MovedFrom, moved.clone(), None)));
+        assert!(tree.find(&moved).is_none());
+
+        std::fs::write(sub.join("arrived"), "").unwrap();
+        assert!(tree.apply_event(&event(Event::MovedTo, sub.join("arrived"), None)));
+        assert!(tree.find(&sub.join("arrived")).is_some());
+
+        let before = tree.clone();
+        assert!(!tree.apply_event(&event(Event::Access, sub.join("new"), None)));
+        assert!(!tree.apply_event(&event(Event::Delete, dir.path().join("missing"), None)));
+        assert_eq!(tree, before);
+    }
+
+    #[test]
+    fn remove_recursive_reaches_any_depth() {
+        let mut root = dir("/rr", vec![
+            file("/rr/top", None),
+            FsNode::Directory(dir("/rr/a", vec![
+                FsNode::Directory(dir("/rr/a/b", vec![file("/rr/a/b/deep", None)])),
+            ])),
+        ]);
+
+        assert!(root.remove_recursive(Path::new("/rr/top")));
+        assert!(root.remove_recursive(Path::new("/rr/a/b/deep")));
+        assert!(root.find(Path::new("/rr/a/b/deep")).is_none());
+        assert!(root.find(Path::new("/rr/a/b")).is_some());
+
+        assert!(!root.remove_recursive(Path::new("/rr/a/missing")));
+        assert!(!root.remove_recursive(Path::new("/rr/nowhere/file")));
+        assert_eq!(root.entry_count(), 2);
+    }
+
+    #[test]
+    fn insert_at_needs_an_existing_parent_dir() {
+        let mut root = dir("/ia", vec![
+            file("/ia/file", None),
+            FsNode::Directory(dir("/ia/a", vec![FsNode::Directory(dir("/ia/a/b", vec![]))])),
+        ]);
+
+        assert!(root.insert_at(Path::new("/ia"), file("/ia/new", None)));
+        assert!(root.insert_at(Path::new("/ia/a/b"), file("/ia/a/b/nested", None)));
+        assert!(root.find(Path::new("/ia/new")).is_some());
+        assert!(root.find(Path::new("/ia/a/b/nested")).is_some());
+
+        assert!(!root.insert_at(Path::new("/ia/missing"), file("/ia/missing/x", None)));
+        assert!(!root.insert_at(Path::new("/ia/file"), file("/ia/file/x", None)));
+        assert!(root.find(Path::new("/ia/missing/x")).is_none());
+    }
+
+    #[test]
+    fn latest_modified_finds_the_newest_deep_file() {
+        let at = |secs| Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        let timed = |path: &str, secs| FsNode::File(FileInfo::new(
+            s!(Path::new(path).file_name().unwrap().to_string_lossy()), PathBuf::from(path), at(secs), None,
+        ));
+
+        let mut deep = dir("/lm/a/b/c", vec![timed("/lm/a/b/c/newest", 300)]);
+        deep.last_modified = at(50);
+        let mut root = dir("/lm", vec![
+            timed("/lm/top", 100),
+            FsNode::Directory(dir("/lm/a", vec![
+                FsNode::Directory(dir("/lm/a/b", vec![FsNode::Directory(deep), timed("/lm/a/b/mid", 200)])),
+            ])),
+        ]);
+        root.last_modified = at(10);
+        assert_eq!(root.latest_modified(), at(300));
+
+        let untimed = dir("/lm", vec![
+            file("/lm/top", None),
+            FsNode::Directory(dir("/lm/a", vec![file("/lm/a/inner", None)])),
+        ]);
+        assert_eq!(untimed.latest_modified(), None);
+    }
+
+    #[test]
+    fn retain_drops_nested_nodes_and_whole_failing_dirs() {
+        let mut root = dir("/rt", vec![
+            file("/rt/keep.rs", None),
+            file("/rt/drop.txt", None),
+            FsNode::Directory(dir("/rt/src", vec![
+                file("/rt/src/lib.rs", None),
+                FsNode::Directory(dir("/rt/src/deep", vec![
+                    file("/rt/src/deep/mod.rs", None),
+                    file("/rt/src/deep/notes.txt", None),
+                ])),
+            ])),
+            FsNode::Directory(dir("/rt/target", vec![file("/rt/target/out.rs", None)])),
+        ]);
+
+        root.retain(|node| match node {
+            FsNode::Directory(d) => d.name != "target",
+            _ => node.path().extension().is_some_and(|ext| ext == "rs"),
+        });
+
+        let kept: Vec<_> = root.iter().map(|node| node.path()).collect();
+        assert_eq!(kept, ["/rt/keep.rs", "/rt/src", "/rt/src/lib.rs", "/rt/src/deep", "/rt/src/deep/mod.rs"]
+            .map(PathBuf::from));
+    }
+
+    #[test]
+    fn extension_histogram_folds_case_and_counts_bare_names() {
+        let root = dir("/eh", vec![
+            file("/eh/main.rs", None),
+            file("/eh/Makefile", None),
+            FsNode::Directory(dir("/eh/sub", vec![
+                file("/eh/sub/OLD.RS", None),
+                file("/eh/sub/LICENSE", None),
+                file("/eh/sub/notes.md", None),
+            ])),
+        ]);
+
+        let histogram = root.extension_histogram();
+        assert_eq!(histogram.len(), 3);
+        assert_eq!(histogram["rs"], 2);
+        assert_eq!(histogram[""], 2);
+        assert_eq!(histogram["md"], 1);
+    }
+
+    #[test]
+    fn diff_skips_only_the_root_snapshot() {
+        let stamped = |path: &str, secs| {
+            let FsNode::File(mut f) = file(path, None) else { unreachable!() };
+            f.last_modified = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+            FsNode::File(f)
+        };
+        let old = dir("/s", vec![stamped("/s/.watcher", 1), FsNode::Directory(dir("/s/sub", vec![]))]);
+        let new = dir("/s", vec![
+            stamped("/s/.watcher", 2),
+            FsNode::Directory(dir("/s/sub", vec![stamped("/s/sub/.watcher", 2)])),
+        ]);
+
+        assert_eq!(old.diff(&new), [Change::Added(PathBuf::from("/s/sub/.watcher"))]);
+        assert_eq!(new.diff(&old), [Change::Removed(PathBuf::from("/s/sub/.watcher"))]);
+    }
+
+    #[test]
+    fn shards_split_the_children_without_losing_any() {
+        let root = dir("/sh", vec![
+            file("/sh/big", Some(50)),
+            file("/sh/small", Some(5)),
+            FsNode::Directory(dir("/sh/a", vec![file("/sh/a/x", Some(30)), file("/sh/a/y", Some(10))])),
+            FsNode::Directory(dir("/sh/b", vec![file("/sh/b/z", Some(20))])),
+        ]);
+
+        for n in [1, 2, 3, 4] {
+            let shards = root.shards(n);
+            assert_eq!(shards.len(), n);
+            assert!(shards.iter().all(|shard| shard.path == root.path && !shard.content.is_empty()));
+            let mut union: Vec<_> = shards.into_iter().flat_map(|shard| shard.content).collect();
+            union.sort_by(|a, b| a.name_ref().cmp(b.name_ref()));
+            let mut children = root.content.clone();
+            children.sort_by(|a, b| a.name_ref().cmp(b.name_ref()));
+            assert_eq!(union, children);
+        }
+
+        // Heaviest first into the lightest shard: 50 + 5 against 40 + 20
+        let sizes: Vec<u64> = root.shards(2).iter().map(|shard| shard.total_size()).collect();
+        assert_eq!(sizes, [55, 60]);
+
+        assert!(root.shards(0).is_empty());
+        assert_eq!(root.shards(10).len(), 4);
+        assert!(dir("/none", vec![]).shards(3).is_empty());
+    }
+
+    #[test]
+    fn json_patch_adds_removes_and_replaces_by_path() {
+        let prev = dir("/jp", vec![
+            file("/jp/keep", Some(1)),
+            file("/jp/grow", Some(1)),
+            file("/jp/gone", Some(1)),
+            file("/jp/flip", Some(1)),
+            FsNode::Directory(dir("/jp/sub", vec![file("/jp/sub/old", Some(1))])),
+        ]);
+        let mut sub = dir("/jp/sub", vec![]);
+        sub.expanded = false;
+        let next = dir("/jp", vec![
+            file("/jp/keep", Some(1)),
+            file("/jp/grow", Some(2)),
+            file("/jp/new", Some(3)),
+            FsNode::Directory(dir("/jp/flip", vec![])),
+            FsNode::Directory(sub),
+        ]);
+
+        let patch = next.json_patch(&prev);
+        let ops: Vec<(&str, &str)> = patch.as_array().unwrap().iter()
+            .map(|op| (op["op"].as_str().unwrap(), op["path"].as_str().unwrap()))
+            .collect();
+        assert_eq!(ops, [
+            ("replace", "/jp/grow"),
+            ("add", "/jp/new"),
+            ("replace", "/jp/flip"),
+            ("replace", "/jp/sub"),
+            ("remove", "/jp/sub/old"),
+            ("remove", "/jp/gone"),
+        ]);
+
+        let patch = patch.as_array().unwrap();
+        assert_eq!(patch[0]["value"], to_json(&next.content[1]));
+        assert_eq!(patch[1]["value"], to_json(&next.content[2]));
+        // The directory is replaced without its content
+        assert_eq!(patch[3]["value"]["expanded"], false);
+        assert_eq!(patch[3]["value"]["content"], serde_json::json!([]));
+        assert!(patch[4].get("value").is_none());
+        assert_eq!(next.json_patch(&next), serde_json::json!([]));
+    }
+
+    #[test]
+    fn age_histogram_puts_bounds_in_the_older_slot_and_future_files_first() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(10 * 86400);
+        let (hour, day) = (Duration::from_secs(3600), Duration::from_secs(86400));
+        let aged = |path: &str, modified: Option<SystemTime>| {
+            let FsNode::File(mut f) = file(path, None) else { unreachable!() };
+            f.last_modified = modified;
+            FsNode::File(f)
+        };
+        let root = dir("/ah", vec![
+            aged("/ah/now", Some(now)),
+            aged("/ah/just_under_an_hour", Some(now - hour + Duration::from_secs(1))),
+            aged("/ah/an_hour", Some(now - hour)),
+            aged("/ah/future", Some(now + day)),
+            aged("/ah/untimed", None),
+            FsNode::Directory(dir("/ah/sub", vec![
+                aged("/ah/sub/a_day", Some(now - day)),
+                aged("/ah/sub/two_days", Some(now - 2 * day)),
+            ])),
+        ]);
+
+        assert_eq!(root.age_histogram(now, &[hour, day]), [3, 1, 2]);
+        assert_eq!(root.age_histogram(now, &[]), [6]);
+        assert_eq!(dir("/empty", vec![]).age_histogram(now, &[hour]), [0, 0]);
+    }
+
+    #[test]
+    fn largest_dir_breaks_ties_by_name_and_needs_a_subdir() {
+        let root = dir("/ld", vec![
+            file("/ld/huge", Some(100)),
+            FsNode::Directory(dir("/ld/b", vec![file("/ld/b/x", Some(20))])),
+            FsNode::Directory(dir("/ld/a", vec![file("/ld/a/x", Some(5)), file("/ld/a/y", Some(15))])),
+            FsNode::Directory(dir("/ld/c", vec![
+                FsNode::Directory(dir("/ld/c/deep", vec![file("/ld/c/deep/x", Some(12))])),
+            ])),
+        ]);
+        assert_eq!(root.largest_dir().map(|d| d.name.as_str()), Some("a"));
+
+        let flat = dir("/flat", vec![file("/flat/only", Some(100))]);
+        assert!(flat.largest_dir().is_none());
+        assert!(dir("/empty", vec![]).largest_dir().is_none());
+    }
+
+    #[test]
+    fn tree_of_paths_keeps_listed_nodes_and_the_dirs_leading_to_them() {
+        let root = dir("/tp", vec![
+            file("/tp/top", None),
+            file("/tp/other", None),
+            FsNode::Directory(dir("/tp/a", vec![
+                file("/tp/a/skip", None),
+                FsNode::Directory(dir("/tp/a/b", vec![file("/tp/a/b/want", None), file("/tp/a/b/not", None)])),
+            ])),
+            FsNode::Directory(dir("/tp/whole", vec![file("/tp/whole/x", None), file("/tp/whole/y", None)])),
+            FsNode::Directory(dir("/tp/unlisted", vec![file("/tp/unlisted/z", None)])),
+        ]);
+        let paths = ["/tp/top", "/tp/a/b/want", "/tp/whole", "/tp/missing", "/tp/a/nope"].map(PathBuf::from);
+
+        let tree = root.tree_of_paths(&paths);
+        let kept: Vec<_> = tree.iter().map(|node| node.path()).collect();
+        assert_eq!(kept, ["/tp/top", "/tp/a", "/tp/a/b", "/tp/a/b/want", "/tp/whole", "/tp/whole/x", "/tp/whole/y"]
+            .map(PathBuf::from));
+        assert_eq!(tree.path, root.path);
+        assert!(root.tree_of_paths(&[]).content.is_empty());
     }
 }
-    
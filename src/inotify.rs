@@ -3,13 +3,22 @@ extern crate libc;
 use core::slice;
 #[cfg(target_os = "linux")]
 use std::{io::{Error, Write}, os::fd::IntoRawFd};
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::{
+    ffi::OsStr, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, time::{Duration, Instant, SystemTime},
+};
+#[cfg(target_os = "linux")]
+use tokio::sync::mpsc::UnboundedSender;
+#[cfg(target_os = "linux")]
+use crate::backend::{Debouncer, FsEvent};
 use serde::{Serialize, Deserialize};
 #[cfg(target_os = "linux")]
 use libc::{
     inotify_init1, 
     IN_NONBLOCK, IN_CLOEXEC, IN_MODIFY, IN_CREATE, IN_ACCESS, 
     IN_ATTRIB, IN_CLOSE_WRITE, IN_CLOSE_NOWRITE, IN_OPEN, 
-    IN_MOVED_FROM, IN_MOVED_TO, IN_DELETE, IN_DELETE_SELF, IN_MOVE_SELF, IN_UNMOUNT, IN_Q_OVERFLOW, IN_IGNORED
+    IN_MOVED_FROM, IN_MOVED_TO, IN_MOVE, IN_DELETE, IN_DELETE_SELF, IN_MOVE_SELF, IN_UNMOUNT, IN_Q_OVERFLOW, IN_IGNORED, IN_ISDIR
 };
 #[allow(unused_imports)]
 use simplicio::*;
@@ -29,51 +38,151 @@ use simplicio::*;
 // TODO: Move this code to a small binary to run separately from this library and primary program
 // reeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee
 #[cfg(target_os = "linux")]
+#[derive(Debug)]
 pub enum INotifyError {
     OSError(Error),
     IOError(Error),
     Utf8Error(std::str::Utf8Error),
 }
 
+#[cfg(target_os = "linux")]
+impl std::fmt::Display for INotifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            INotifyError::OSError(e) => write!(f, "{}", e),
+            INotifyError::IOError(e) => write!(f, "{}", e),
+            INotifyError::Utf8Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl std::error::Error for INotifyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            INotifyError::OSError(e) | INotifyError::IOError(e) => Some(e),
+            INotifyError::Utf8Error(e) => Some(e),
+        }
+    }
+}
+
+// inotify's masks, which number the events on every platform
+#[cfg(not(target_os = "linux"))]
+const IN_ACCESS: u32 = 0x00000001;
+#[cfg(not(target_os = "linux"))]
+const IN_MODIFY: u32 = 0x00000002;
+#[cfg(not(target_os = "linux"))]
+const IN_ATTRIB: u32 = 0x00000004;
+#[cfg(not(target_os = "linux"))]
+const IN_CLOSE_WRITE: u32 = 0x00000008;
+#[cfg(not(target_os = "linux"))]
+const IN_CLOSE_NOWRITE: u32 = 0x00000010;
+#[cfg(not(target_os = "linux"))]
+const IN_OPEN: u32 = 0x00000020;
+#[cfg(not(target_os = "linux"))]
+const IN_MOVED_FROM: u32 = 0x00000040;
+#[cfg(not(target_os = "linux"))]
+const IN_MOVED_TO: u32 = 0x00000080;
+#[cfg(not(target_os = "linux"))]
+const IN_MOVE: u32 = IN_MOVED_FROM | IN_MOVED_TO;
+#[cfg(not(target_os = "linux"))]
+const IN_CREATE: u32 = 0x00000100;
+#[cfg(not(target_os = "linux"))]
+const IN_DELETE: u32 = 0x00000200;
+#[cfg(not(target_os = "linux"))]
+const IN_DELETE_SELF: u32 = 0x00000400;
+#[cfg(not(target_os = "linux"))]
+const IN_MOVE_SELF: u32 = 0x00000800;
+#[cfg(not(target_os = "linux"))]
+const IN_UNMOUNT: u32 = 0x00002000;
+#[cfg(not(target_os = "linux"))]
+const IN_Q_OVERFLOW: u32 = 0x00004000;
+#[cfg(not(target_os = "linux"))]
+const IN_IGNORED: u32 = 0x00008000;
+
+/// Serialized by name in snake_case, e.g. `"modify"` or `"close_write"`.
+/// Each variant is numbered by its inotify mask on every platform; other
+/// backends map their own notifications onto the closest ones.
 #[repr(u32)]
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Event {
-    #[cfg(target_os = "linux")]
     Access = IN_ACCESS,              // 0x00000001   1
-    #[cfg(target_os = "linux")]
     Modify = IN_MODIFY,              // 0x00000002   2
-    #[cfg(target_os = "linux")]
     Attrib = IN_ATTRIB,              // 0x00000004   4
-    #[cfg(target_os = "linux")]
     CloseWrite = IN_CLOSE_WRITE,     // 0x00000008   8
-    #[cfg(target_os = "linux")]
     CloseNoWrite = IN_CLOSE_NOWRITE, // 0x00000010   16
-    #[cfg(target_os = "linux")]
     Open = IN_OPEN,                  // 0x00000020   32
-    #[cfg(target_os = "linux")]
     MovedFrom = IN_MOVED_FROM,       // 0x00000040   64
-    #[cfg(target_os = "linux")]
     MovedTo = IN_MOVED_TO,           // 0x00000080   128
-    #[cfg(target_os = "linux")]
     Create = IN_CREATE,              // 0x00000100   256
-    #[cfg(target_os = "linux")]
     Delete = IN_DELETE,              // 0x00000200   512
-    #[cfg(target_os = "linux")]
     DeleteSelf = IN_DELETE_SELF,     // 0x00000400   1024
-    #[cfg(target_os = "linux")]
     MoveSelf = IN_MOVE_SELF,         // 0x00000800   2048
-    #[cfg(target_os = "linux")]
     Unmount = IN_UNMOUNT,            // 0x00002000   8192
-    #[cfg(target_os = "linux")]
+    /// The queue filled up and later events were dropped. It belongs to
+    /// no watch, so its `wd` is -1 and it has no name or path.
     Overflow = IN_Q_OVERFLOW,        // 0x00004000   16384
-    #[cfg(target_os = "linux")]
     Ignored = IN_IGNORED,            // 0x00008000   32768
+    /// A `MovedFrom` joined with its `MovedTo`; never reported by inotify
+    /// itself, which only gives the halves
+    Renamed = IN_MOVE,               // 0x000000C0   192
+    #[serde(rename = "unknown")]
     Uknown = 0,
 }
+/// Events watched when none are requested
+#[cfg(target_os = "linux")]
+pub const DEFAULT_EVENTS: &[Event] = &[
+    Event::Create, Event::Modify, Event::Delete, Event::MovedFrom, Event::MovedTo,
+];
+
+/// How long a `MovedFrom` waits for its `MovedTo` before it is reported on
+/// its own
 #[cfg(target_os = "linux")]
+pub const RENAME_WINDOW: Duration = Duration::from_millis(50);
+
+/// Longest the channel listeners sleep before checking whether to stop
+#[cfg(target_os = "linux")]
+pub(crate) const LISTEN_TICK: Duration = Duration::from_millis(100);
+
+/// Size of the buffer events are read into, room for 32 records with the
+/// longest possible name
+#[cfg(target_os = "linux")]
+const EVENT_BUFFER: usize =
+    32 * (std::mem::size_of::<libc::inotify_event>() + libc::NAME_MAX as usize + 1);
+
+/// Events watched on a single file when none are requested
+#[cfg(target_os = "linux")]
+pub const FILE_EVENTS: &[Event] = &[Event::Modify, Event::CloseWrite, Event::DeleteSelf];
+
+impl Event {
+    /// The name the event serializes as
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Access => "access",
+            Event::Modify => "modify",
+            Event::Attrib => "attrib",
+            Event::CloseWrite => "close_write",
+            Event::CloseNoWrite => "close_no_write",
+            Event::Open => "open",
+            Event::MovedFrom => "moved_from",
+            Event::MovedTo => "moved_to",
+            Event::Create => "create",
+            Event::Delete => "delete",
+            Event::DeleteSelf => "delete_self",
+            Event::MoveSelf => "move_self",
+            Event::Unmount => "unmount",
+            Event::Overflow => "overflow",
+            Event::Ignored => "ignored",
+            Event::Renamed => "renamed",
+            Event::Uknown => "unknown",
+        }
+    }
+}
+
 impl std::fmt::Display for Event {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", *self as u32)
+        write!(f, "{}", self.name())
     }
 }
 
@@ -83,7 +192,7 @@ impl From<u32> for Event {
         match mask {
             1 | 2 | 4 | 8 | 16 | 32 | 64 | 128 | 256 | 512 | 
             1024 | 2048 | 8192 | 16384 | 32768 => unsafe { 
-                std::mem::transmute(mask) 
+                std::mem::transmute::<u32, Event>(mask) 
             },
             _ => Self::Uknown, 
         }
@@ -111,7 +220,7 @@ impl std::ops::BitOr for Event {
     type Output = u32;
 
     fn bitor(self, rhs: Self) -> Self::Output {
-        self | rhs 
+        self as u32 | rhs as u32
     }
 }
 
@@ -123,6 +232,16 @@ pub(crate) struct INotify {
     pub(crate) path: String,
     pub(crate) events: Vec<Event>,
     pub(crate) watch_ids: Vec<i32>,
+    pub(crate) watch_paths: HashMap<i32, String>,
+    /// Watch new subdirectories as they appear, with their parent's mask
+    #[serde(default)]
+    pub(crate) recursive: bool,
+    #[serde(default)]
+    pub(crate) watch_masks: HashMap<i32, u32>,
+    /// `MovedFrom` events waiting for their `MovedTo`, by cookie
+    #[cfg(target_os = "linux")]
+    #[serde(skip)]
+    pub(crate) pending_moves: Vec<(u32, FsEvent, Instant)>,
 }
 
 #[cfg(target_os = "linux")]
@@ -139,25 +258,17 @@ impl INotify {
             path: s!(path),
             events: vec![],
             watch_ids: vec![],
+            watch_paths: HashMap::new(),
+            recursive: false,
+            watch_masks: HashMap::new(),
+            pending_moves: vec![],
         })
     }
 
-    pub(crate) fn add(&mut self, path: &str) -> Result<Self, INotifyError> {
-        let c_path = std::ffi::CString::new(path)
-            .expect("CString::new failed");
-
-        let watch_id = unsafe { 
-            libc::inotify_add_watch(
-                self.id, 
-                c_path.as_ptr(), 
-                Event::Modify | Event::Create 
-            )};
-        self.watch_ids.push(watch_id);
-        return Ok(self.clone())
-    }
-
     /// Create a daemon to sit in the root path and catch the inotify calls
     /// Set up prior to inotify
+    // Kept for the daemon binary planned at the top of this file
+    #[allow(dead_code)]
     pub(crate) fn daemonize(&mut self) -> Result<Self, INotifyError> {
         unsafe { 
             // Fork program for daemon
@@ -175,7 +286,7 @@ impl INotify {
                     libc::umask(0);
                     // Change child's current working directory to root
                     std::env::set_current_dir("/")
-                        .map_err(|e| INotifyError::IOError(e))?;
+                        .map_err(INotifyError::IOError)?;
 
                     // Create/Open the log file
                     let log = match std::fs::File::create(&self.path) {
@@ -201,49 +312,606 @@ impl INotify {
         }
     }
 
-    pub(crate) fn listen(&self) -> Result<(), INotifyError> {
-        let mut buffer = [0u8; 5120]; // Buffer for reading events 5kB
+    /// Appends every event to the log file at `path`, sleeping in wait()
+    /// while none are queued
+    // Kept for the daemon binary planned at the top of this file
+    #[allow(dead_code)]
+    pub(crate) fn listen(&mut self) -> Result<(), INotifyError> {
+        loop {
+            // Wakes in time to report a move whose other half never came
+            let timeout = match self.pending_moves.is_empty() {
+                true => -1,
+                false => RENAME_WINDOW.as_millis() as i32,
+            };
+            self.wait(timeout)?;
+            for event in self.read_events()? {
+                let output = format!("{}|{}", event.event, event.path.display());
+
+                // Create/Open the log file
+                let mut log = match std::fs::OpenOptions::new()
+                    .read(true).append(true).open(&self.path) {
+                    Ok(log) => log,
+                    Err(e) => return Err(INotifyError::IOError(e)),
+                };
+
+                writeln!(log, "{}", output).map_err(INotifyError::IOError)?;
+            }
+        }
+    }
+
+    /// listen() delivering each event over `sender` instead of the log file,
+    /// coalesced per path over `debounce` when it isn't zero. Returns within
+    /// LISTEN_TICK of the receiving end being dropped.
+    pub(crate) fn listen_channel(
+        &mut self, sender: UnboundedSender<FsEvent>, debounce: Duration
+    ) -> Result<(), INotifyError> {
+        return self.listen_with(debounce, |events| {
+            !sender.is_closed() && events.into_iter().all(|event| sender.send(event).is_ok())
+        });
+    }
+
+    /// listen_channel() sending everything a tick makes ready as one batch
+    pub(crate) fn listen_batches(
+        &mut self, sender: UnboundedSender<Vec<FsEvent>>, debounce: Duration
+    ) -> Result<(), INotifyError> {
+        return self.listen_with(debounce, |events| {
+            !sender.is_closed() && (events.is_empty() || sender.send(events).is_ok())
+        });
+    }
+
+    /// Reads events until `tick` returns false, handing it what is ready at
+    /// each wake-up: everything one read drained, or with a `debounce`, the
+    /// events whose path has been quiet for it. It is called at least every
+    /// LISTEN_TICK, with no events when nothing is ready, so it can stop
+    /// listening on its own.
+    pub(crate) fn listen_with<F: FnMut(Vec<FsEvent>) -> bool>(
+        &mut self, debounce: Duration, mut tick: F
+    ) -> Result<(), INotifyError> {
+        let mut debouncer = Debouncer::new(debounce);
+        loop {
+            self.wait(LISTEN_TICK.as_millis() as i32)?;
+            let events = self.read_events()?;
+            let events = match debounce.is_zero() {
+                true => events,
+                false => {
+                    let now = Instant::now();
+                    for event in events { debouncer.push(event, now); }
+                    debouncer.ready(now)
+                },
+            };
+            if !tick(events) { return Ok(()); }
+        }
+    }
+
+    // Blocks until events are queued or `timeout` milliseconds pass, forever
+    // when it is negative
+    pub(crate) fn wait(&self, timeout: i32) -> Result<(), INotifyError> {
+        let mut poll = libc::pollfd { fd: self.id, events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut poll, 1, timeout) } == -1 {
+            let error = Error::last_os_error();
+            if error.raw_os_error() == Some(libc::EINTR) { return Ok(()); }
+            return Err(INotifyError::OSError(error));
+        }
+        return Ok(());
+    }
+
+    /// Adds a watch on `path` for the given events. When `events` is empty
+    /// a directory is watched for creations, modifications, deletions and
+    /// moves, and a file for modifications, finished writes and its own
+    /// deletion. Events on a watched file carry an empty name. In recursive
+    /// mode the subdirectories already under a directory are watched too,
+    /// and `Create` and `MovedTo` are always included so new ones can be
+    /// picked up.
+    pub(crate) fn add(&mut self, path: &str, events: &[Event]) -> Result<i32, INotifyError> {
+        let is_dir = std::fs::metadata(path).map_err(INotifyError::IOError)?.is_dir();
+        let events = match (events.is_empty(), is_dir) {
+            (true, true) => DEFAULT_EVENTS,
+            (true, false) => FILE_EVENTS,
+            (false, _) => events,
+        };
+        let mask = events.iter().fold(0, |mask, event| mask | *event as u32);
+        return self.add_mask(path, mask);
+    }
+
+    fn add_mask(&mut self, path: &str, mut mask: u32) -> Result<i32, INotifyError> {
+        let recurse = self.recursive && std::path::Path::new(path).is_dir();
+        if recurse { mask |= IN_CREATE | IN_MOVED_TO; }
+        let c_path = std::ffi::CString::new(path)
+            .map_err(|e| INotifyError::IOError(Error::other(e)))?;
+
+        let watch_id = unsafe { libc::inotify_add_watch(self.id, c_path.as_ptr(), mask) };
+        if watch_id == -1 {
+            return Err(INotifyError::OSError(Error::last_os_error()));
+        }
+        if !self.watch_ids.contains(&watch_id) { self.watch_ids.push(watch_id); }
+        self.watch_paths.insert(watch_id, s!(path));
+        self.watch_masks.insert(watch_id, mask);
+
+        if recurse {
+            let entries = std::fs::read_dir(path).map_err(INotifyError::IOError)?;
+            for entry in entries.flatten() {
+                // Symlinked directories are left alone, as in the walk
+                if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                    self.add_mask(&s!(entry.path().display()), mask)?;
+                }
+            }
+        }
+        return Ok(watch_id);
+    }
+
+    /// Stops watching the descriptor returned when it was added
+    pub(crate) fn remove(&mut self, watch_id: i32) -> Result<(), INotifyError> {
+        if unsafe { libc::inotify_rm_watch(self.id, watch_id) } == -1 {
+            return Err(INotifyError::OSError(Error::last_os_error()));
+        }
+        self.forget(watch_id);
+        return Ok(());
+    }
+
+    /// remove() by the path that was added
+    pub(crate) fn remove_path(&mut self, path: &str) -> Result<(), INotifyError> {
+        let watch_id = self.watch_paths.iter()
+            .find(|(_, watched)| watched.as_str() == path)
+            .map(|(watch_id, _)| *watch_id)
+            .ok_or(INotifyError::OSError(Error::from_raw_os_error(libc::EINVAL)))?;
+        return self.remove(watch_id);
+    }
+
+    // Forgets a watch the kernel has dropped, e.g. its directory was deleted
+    fn forget(&mut self, watch_id: i32) {
+        self.watch_ids.retain(|id| *id != watch_id);
+        self.watch_paths.remove(&watch_id);
+        self.watch_masks.remove(&watch_id);
+    }
+
+    /// Reads the events currently queued on the instance, empty if none are.
+    ///
+    /// A `MovedFrom` and `MovedTo` sharing a cookie are joined into one
+    /// `Renamed` event at the new path, with `from` set to the old one. A
+    /// `MovedFrom` is held back for up to RENAME_WINDOW waiting for its
+    /// other half, and is then reported on its own by a later read; a
+    /// `MovedTo` without one is reported as it arrives. Either way they
+    /// mean a deletion and a creation.
+    pub(crate) fn read_events(&mut self) -> Result<Vec<FsEvent>, INotifyError> {
+        let now = Instant::now();
+        let mut events = vec![];
+        self.pending_moves.retain(|(_, event, held)| {
+            if now.duration_since(*held) < RENAME_WINDOW { return true; }
+            events.push(event.clone());
+            false
+        });
+
+        let mut buffer = [0u8; EVENT_BUFFER];
+        let time = SystemTime::now();
+
+        // A read stops at a full buffer, so keep reading until the fd is empty
         loop {
-            for id in self.watch_ids.clone().into_iter() {
-                // Read the events in the watched directories, store in buffer
-                let bytes_read = unsafe {
-                    libc::read(
-                        id, 
-                        buffer.as_mut_ptr() as *mut _, 
-                        buffer.len())};
-                
-                if bytes_read == -1 { // Error reading
-                    return Err(INotifyError::OSError(Error::last_os_error()));
-                } else if bytes_read > 0 { // Content exists
-                    // Iterate through the buffer and read each item
-                    let mut i = 0;
-                    while i < bytes_read as usize {
-                        let size = std::mem::size_of::<libc::inotify_event>();
-                        let event = unsafe { &*(buffer.as_ptr() as *const libc::inotify_event) };
-                        let mask = event.mask;
-                        let file_name = unsafe { slice::from_raw_parts(
-                                buffer.as_ptr().add(i + size), event.len as usize) };
-
-                        let file_name = std::str::from_utf8(file_name)
-                            .map_err(|e| INotifyError::Utf8Error(e))?;
-
-                        let output = format!("{}|{}", Event::from(mask), file_name);
-
-                        // Create/Open the log file
-                        let mut log = match std::fs::OpenOptions::new()
-                            .read(true).append(true).open(&self.path) {
-                            Ok(log) => log,
-                            Err(e) => return Err(INotifyError::IOError(e)),
-                        };
-
-                        write!(log, "{}", output).map_err(|e| INotifyError::IOError(e))?;
-
-
-                        i += size + event.len as usize;
+            let bytes_read = unsafe {
+                libc::read(
+                    self.id, 
+                    buffer.as_mut_ptr() as *mut _, 
+                    buffer.len())};
+
+            if bytes_read == -1 { // Error reading
+                let error = Error::last_os_error();
+                // Nothing more queued on the non-blocking fd
+                if error.raw_os_error() == Some(libc::EAGAIN) { break; }
+                return Err(INotifyError::OSError(error));
+            }
+            let bytes_read = bytes_read as usize;
+            if bytes_read == 0 { break; }
+
+            self.read_records(&buffer[..bytes_read], now, time, &mut events);
+        }
+        return Ok(events);
+    }
+
+    /// Points the watches on `from` and the directories under it at `to`
+    /// once `from` is renamed. The kernel keeps watching the same
+    /// directories, so only the paths events are reported under change.
+    fn rebase_watches(&mut self, from: &Path, to: &Path) {
+        for dir in self.watch_paths.values_mut() {
+            let Ok(rest) = Path::new(dir.as_str()).strip_prefix(from) else { continue; };
+            *dir = match rest.as_os_str().is_empty() {
+                true => s!(to.display()),
+                false => s!(to.join(rest).display()),
+            };
+        }
+    }
+
+    /// Turns the inotify_event records in `data` into events, pairing up
+    /// moves and watching new directories. A record running past the end
+    /// of `data` ends the batch.
+    fn read_records(&mut self, data: &[u8], now: Instant, time: SystemTime, events: &mut Vec<FsEvent>) {
+        let size = std::mem::size_of::<libc::inotify_event>();
+        let mut i = 0;
+        while i + size <= data.len() {
+            let event = unsafe { std::ptr::read_unaligned(
+                data.as_ptr().add(i) as *const libc::inotify_event) };
+            // The kernel only returns whole records, but a bad length must
+            // not read past the data
+            let end = i + size + event.len as usize;
+            if end > data.len() { break; }
+
+            // The name is NUL padded up to `len`, and may not be UTF-8
+            let raw_name = &data[i + size..end];
+            let raw_name = &raw_name[..raw_name.iter().position(|b| *b == 0).unwrap_or(raw_name.len())];
+            let file_name = String::from_utf8_lossy(raw_name);
+            let file_name = file_name.as_ref();
+
+            let path = match self.watch_paths.get(&event.wd) {
+                Some(dir) if raw_name.is_empty() => PathBuf::from(dir),
+                Some(dir) => Path::new(dir).join(OsStr::from_bytes(raw_name)),
+                None => PathBuf::from(OsStr::from_bytes(raw_name)),
+            };
+            let fs_event = FsEvent {
+                wd: event.wd,
+                event: Event::from(event.mask & !IN_ISDIR),
+                name: s!(file_name),
+                path,
+                from: None,
+                time,
+            };
+            match fs_event.event {
+                Event::MovedFrom if event.cookie != 0 => {
+                    self.pending_moves.push((event.cookie, fs_event, now));
+                },
+                Event::MovedTo => {
+                    let pair = self.pending_moves.iter()
+                        .position(|(cookie, _, _)| *cookie == event.cookie && event.cookie != 0);
+                    match pair {
+                        Some(index) => {
+                            let (_, moved_from, _) = self.pending_moves.remove(index);
+                            if event.mask & IN_ISDIR != 0 {
+                                self.rebase_watches(&moved_from.path, &fs_event.path);
+                            }
+                            events.push(FsEvent {
+                                event: Event::Renamed,
+                                from: Some(moved_from.path),
+                                ..fs_event
+                            });
+                        },
+                        None => events.push(fs_event),
+                    }
+                },
+                _ => events.push(fs_event),
+            }
+
+            // Start watching directories that appeared under a watch
+            let new_dir = event.mask & IN_ISDIR != 0
+                && event.mask & (IN_CREATE | IN_MOVED_TO) != 0;
+            if self.recursive && new_dir {
+                if let (Some(dir), Some(mask)) = (
+                    self.watch_paths.get(&event.wd).cloned(),
+                    self.watch_masks.get(&event.wd).copied()
+                ) {
+                    // It may already be gone again; nothing to watch then.
+                    // Watches are kept by UTF-8 path, so a directory whose
+                    // name isn't one can't be added.
+                    if let Ok(name) = std::str::from_utf8(raw_name) {
+                        _ = self.add_mask(&format!("{}/{}", dir, name), mask);
                     }
                 }
             }
+            if event.mask & IN_IGNORED != 0 { self.forget(event.wd); }
+
+            i = end;
         }
     }
 }
 
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    /// An inotify_event record for `wd` naming `name`, NUL padded to 4 bytes
+    fn record(wd: i32, mask: u32, name: &[u8]) -> Vec<u8> {
+        return move_record(wd, mask, 0, name);
+    }
+
+    fn move_record(wd: i32, mask: u32, cookie: u32, name: &[u8]) -> Vec<u8> {
+        let len = (name.len() + 1).next_multiple_of(4);
+        let mut data = vec![];
+        data.extend(wd.to_ne_bytes());
+        data.extend(mask.to_ne_bytes());
+        data.extend(cookie.to_ne_bytes());
+        data.extend((len as u32).to_ne_bytes());
+        data.extend(name);
+        data.resize(data.len() + len - name.len(), 0);
+        return data;
+    }
+
+    fn watching(dir: &str) -> INotify {
+        let mut inotify = INotify::new(dir).unwrap();
+        inotify.watch_paths.insert(1, s!(dir));
+        return inotify;
+    }
+
+    fn read(inotify: &mut INotify, data: &[u8]) -> Vec<FsEvent> {
+        let mut events = vec![];
+        inotify.read_records(data, Instant::now(), SystemTime::now(), &mut events);
+        return events;
+    }
+
+    #[test]
+    fn or_combines_event_masks() {
+        assert_eq!(Event::Create | Event::Delete, IN_CREATE | IN_DELETE);
+        assert_eq!(Event::MovedFrom | Event::MovedTo, IN_MOVE);
+    }
+
+    #[test]
+    fn a_record_cut_short_ends_the_batch() {
+        let mut inotify = watching("/watched");
+        let mut data = record(1, IN_CREATE, b"whole");
+        let partial = record(1, IN_CREATE, b"cut short");
+        data.extend(&partial[..partial.len() - 4]);
+
+        let events = read(&mut inotify, &data);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, "whole");
+
+        // Not even a whole header
+        assert!(read(&mut inotify, &partial[..8]).is_empty());
+    }
+
+    #[test]
+    fn a_name_that_is_not_utf8_keeps_the_rest_of_the_batch() {
+        let mut inotify = watching("/watched");
+        let mut data = record(1, IN_CREATE, b"bad\xffname");
+        data.extend(record(1, IN_DELETE, b"after"));
+
+        let events = read(&mut inotify, &data);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].name, "bad\u{fffd}name");
+        // The path keeps the bytes, so it still names the file on disk
+        assert_eq!(events[0].path, Path::new("/watched").join(OsStr::from_bytes(b"bad\xffname")));
+        assert_eq!(events[1].name, "after");
+    }
+
+    #[test]
+    fn renaming_a_watched_dir_moves_the_watches_under_it() {
+        let mut inotify = watching("/w");
+        inotify.watch_paths.insert(2, s!("/w/old"));
+        inotify.watch_paths.insert(3, s!("/w/old/deep"));
+        inotify.watch_paths.insert(4, s!("/w/older"));
+
+        let mut data = move_record(1, IN_MOVED_FROM | IN_ISDIR, 7, b"old");
+        data.extend(move_record(1, IN_MOVED_TO | IN_ISDIR, 7, b"new"));
+        data.extend(record(3, IN_CREATE, b"file"));
+        let events = read(&mut inotify, &data);
+
+        assert!(matches!(events[0].event, Event::Renamed));
+        assert_eq!(inotify.watch_paths[&2], "/w/new");
+        assert_eq!(inotify.watch_paths[&3], "/w/new/deep");
+        // Shares the prefix as a string, but isn't under the moved dir
+        assert_eq!(inotify.watch_paths[&4], "/w/older");
+        assert_eq!(events[1].path, Path::new("/w/new/deep/file"));
+    }
+
+    #[test]
+    fn reads_a_create_from_the_instance_fd() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut inotify = INotify::new(dir.path().to_str().unwrap()).unwrap();
+        let watch_id = inotify.add(dir.path().to_str().unwrap(), &[]).unwrap();
+
+        std::fs::write(dir.path().join("created"), "").unwrap();
+        inotify.wait(1000).unwrap();
+        let events = inotify.read_events().unwrap();
+        let create = events.iter().find(|event| matches!(event.event, Event::Create)).unwrap();
+        assert_eq!((create.wd, create.name.as_str()), (watch_id, "created"));
+        assert_eq!(create.path, dir.path().join("created"));
+    }
+
+    #[test]
+    fn recursive_mode_watches_dirs_created_after_it_starts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut inotify = INotify::new(dir.path().to_str().unwrap()).unwrap();
+        inotify.recursive = true;
+        inotify.add(dir.path().to_str().unwrap(), &[]).unwrap();
+
+        let nested = dir.path().join("outer").join("inner");
+        std::fs::create_dir(dir.path().join("outer")).unwrap();
+        inotify.wait(1000).unwrap();
+        inotify.read_events().unwrap();
+        std::fs::create_dir(&nested).unwrap();
+        inotify.wait(1000).unwrap();
+        inotify.read_events().unwrap();
+        assert_eq!(inotify.watch_ids.len(), 3);
+
+        std::fs::write(nested.join("file"), "").unwrap();
+        inotify.wait(1000).unwrap();
+        let events = inotify.read_events().unwrap();
+        assert!(events.iter().any(|event| matches!(event.event, Event::Create)
+            && event.path == nested.join("file")), "{events:?}");
+    }
+
+    #[test]
+    fn a_removed_watch_delivers_nothing_more() {
+        let (kept, dropped) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let mut inotify = INotify::new(kept.path().to_str().unwrap()).unwrap();
+        inotify.add(kept.path().to_str().unwrap(), &[]).unwrap();
+        let watch_id = inotify.add(dropped.path().to_str().unwrap(), &[]).unwrap();
+
+        inotify.remove(watch_id).unwrap();
+        assert!(!inotify.watch_ids.contains(&watch_id));
+        assert!(inotify.remove(watch_id).is_err());
+
+        std::fs::write(dropped.path().join("unseen"), "").unwrap();
+        std::fs::write(kept.path().join("seen"), "").unwrap();
+        inotify.wait(1000).unwrap();
+        let mut names: Vec<String> = inotify.read_events().unwrap().into_iter()
+            .filter(|event| !matches!(event.event, Event::Ignored))
+            .map(|event| event.name)
+            .collect();
+        names.dedup();
+        assert_eq!(names, ["seen"]);
+
+        inotify.remove_path(kept.path().to_str().unwrap()).unwrap();
+        assert!(inotify.watch_ids.is_empty());
+    }
+
+    #[test]
+    fn a_delete_only_watch_ignores_creates() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut inotify = INotify::new(dir.path().to_str().unwrap()).unwrap();
+        inotify.add(dir.path().to_str().unwrap(), &[Event::Delete]).unwrap();
+
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        inotify.wait(100).unwrap();
+        assert!(inotify.read_events().unwrap().is_empty());
+
+        std::fs::remove_file(dir.path().join("file")).unwrap();
+        inotify.wait(1000).unwrap();
+        let events = inotify.read_events().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0].event, Event::Delete));
+        assert_eq!(events[0].name, "file");
+    }
+
+    #[test]
+    fn inotify_errors_expose_the_os_error() {
+        let error = INotifyError::OSError(Error::from_raw_os_error(libc::EBADF));
+        let source = (&error as &dyn std::error::Error).source().unwrap();
+        assert_eq!(source.downcast_ref::<Error>().unwrap().raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn a_watched_file_reports_modify_with_an_empty_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config");
+        std::fs::write(&config, "before").unwrap();
+        let mut inotify = INotify::new(config.to_str().unwrap()).unwrap();
+        inotify.add(config.to_str().unwrap(), &[]).unwrap();
+
+        std::fs::write(&config, "after").unwrap();
+        inotify.wait(1000).unwrap();
+        let events = inotify.read_events().unwrap();
+        let modify = events.iter().find(|event| matches!(event.event, Event::Modify)).unwrap();
+        assert_eq!((modify.name.as_str(), &modify.path), ("", &config));
+    }
+
+    #[test]
+    fn event_times_never_go_backwards() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut inotify = INotify::new(dir.path().to_str().unwrap()).unwrap();
+        inotify.add(dir.path().to_str().unwrap(), &[Event::Create]).unwrap();
+
+        let mut events = vec![];
+        for i in 0..5 {
+            std::fs::write(dir.path().join(i.to_string()), "").unwrap();
+            inotify.wait(1000).unwrap();
+            events.extend(inotify.read_events().unwrap());
+        }
+        assert_eq!(events.len(), 5);
+        assert!(events.windows(2).all(|pair| pair[0].time <= pair[1].time));
+
+        let json = serde_json::to_string(&events[0]).unwrap();
+        let loaded: FsEvent = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.time, events[0].time);
+    }
+
+    #[test]
+    fn events_serialize_and_display_by_name() {
+        assert_eq!(serde_json::to_string(&Event::Modify).unwrap(), r#""modify""#);
+        assert!(matches!(serde_json::from_str(r#""modify""#).unwrap(), Event::Modify));
+        assert_eq!(Event::Modify.to_string(), "modify");
+
+        let json = serde_json::to_string(&Event::CloseWrite).unwrap();
+        assert_eq!(json, r#""close_write""#);
+        assert!(matches!(serde_json::from_str(&json).unwrap(), Event::CloseWrite));
+        assert!(serde_json::from_str::<Event>("2").is_err());
+    }
+
+    #[test]
+    fn an_overflow_record_decodes_as_overflow() {
+        // The kernel sends it with wd -1 and no name at all
+        let mut data = move_record(-1, IN_Q_OVERFLOW, 0, b"");
+        data.truncate(16);
+        data[12..16].copy_from_slice(&0u32.to_ne_bytes());
+        data.extend(record(1, IN_CREATE, b"after"));
+
+        let events = read(&mut watching("/watched"), &data);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0].event, Event::Overflow));
+        assert_eq!((events[0].wd, events[0].name.as_str()), (-1, ""));
+        assert_eq!(events[0].path, PathBuf::new());
+        assert_eq!(events[1].name, "after");
+    }
+
+    #[test]
+    fn wait_sleeps_while_idle_and_wakes_on_an_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut inotify = INotify::new(dir.path().to_str().unwrap()).unwrap();
+        inotify.add(dir.path().to_str().unwrap(), &[Event::Create]).unwrap();
+
+        let cpu = || {
+            let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+            unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) };
+            return Duration::from_secs(usage.ru_utime.tv_sec as u64 + usage.ru_stime.tv_sec as u64)
+                + Duration::from_micros(usage.ru_utime.tv_usec as u64 + usage.ru_stime.tv_usec as u64);
+        };
+        let (started, cpu_before) = (Instant::now(), cpu());
+        inotify.wait(300).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        assert!(cpu() - cpu_before < Duration::from_millis(50));
+        assert!(inotify.read_events().unwrap().is_empty());
+
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        let started = Instant::now();
+        inotify.wait(5000).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(inotify.read_events().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn renaming_a_file_reports_one_renamed_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut inotify = INotify::new(dir.path().to_str().unwrap()).unwrap();
+        inotify.add(dir.path().to_str().unwrap(), &[Event::MovedFrom, Event::MovedTo]).unwrap();
+        std::fs::write(dir.path().join("old"), "").unwrap();
+
+        std::fs::rename(dir.path().join("old"), dir.path().join("new")).unwrap();
+        // Read on past the window, so an unpaired half would show up too
+        let mut events = vec![];
+        let deadline = Instant::now() + RENAME_WINDOW * 3;
+        while Instant::now() < deadline {
+            inotify.wait(RENAME_WINDOW.as_millis() as i32).unwrap();
+            events.extend(inotify.read_events().unwrap());
+        }
+
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0].event, Event::Renamed));
+        assert_eq!(events[0].from, Some(dir.path().join("old")));
+        assert_eq!(events[0].path, dir.path().join("new"));
+        assert_eq!(events[0].name, "new");
+        assert!(inotify.pending_moves.is_empty());
+    }
+
+    #[test]
+    fn one_read_drains_more_events_than_the_buffer_holds() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut inotify = INotify::new(dir.path().to_str().unwrap()).unwrap();
+        inotify.add(dir.path().to_str().unwrap(), &[Event::Create]).unwrap();
+
+        // Every tenth name is near NAME_MAX, together far more than EVENT_BUFFER
+        let names: Vec<String> = (0..600).map(|i| match i % 10 {
+            0 => format!("{:0>250}", i),
+            _ => format!("file{}", i),
+        }).collect();
+        let bytes: usize = names.iter()
+            .map(|name| std::mem::size_of::<libc::inotify_event>() + name.len() + 1)
+            .sum();
+        assert!(bytes > 3 * EVENT_BUFFER);
+        for name in names.iter() { std::fs::write(dir.path().join(name), "").unwrap(); }
+
+        inotify.wait(1000).unwrap();
+        let events = inotify.read_events().unwrap();
+        let mut read: Vec<&str> = events.iter().map(|event| event.name.as_str()).collect();
+        assert!(events.iter().all(|event| matches!(event.event, Event::Create)));
+        assert!(events.iter().all(|event| event.path == dir.path().join(&event.name)));
+        read.sort();
+        let mut expected: Vec<&str> = names.iter().map(String::as_str).collect();
+        expected.sort();
+        assert_eq!(read, expected);
+    }
+}
@@ -1,6 +1,27 @@
+#![allow(clippy::needless_return)]
+
 pub mod fs_node;
 pub mod watcher;
 pub mod inotify;
+pub mod backend;
+pub mod util;
+mod snapshot;
+#[cfg(target_os = "linux")]
+pub mod live;
+#[cfg(target_os = "linux")]
+pub mod stream;
+#[cfg(target_os = "windows")]
+pub mod windows;
+#[cfg(target_os = "macos")]
+pub mod macos;
 
-pub use fs_node::{DirInfo, FileInfo, FsNode, N};
-pub use watcher::Watcher;
+pub use fs_node::{Change, DirInfo, FileInfo, FsNode, GitStatus, FsNodeIter, FsNodeDepthIter, SortKey, SortOrder, SymlinkInfo, TreeConfig, TreeStyle, N};
+pub use watcher::{Watcher, WatcherBuilder, WalkStats};
+pub use util::format_bytes;
+#[cfg(target_os = "linux")]
+pub use live::LiveTreeRenderer;
+#[cfg(target_os = "linux")]
+pub use stream::EventStream;
+#[cfg(target_os = "linux")]
+pub use watcher::OnChange;
+pub use backend::{FileWatchBackend, FsEvent, WatchId, BackendError};
@@ -0,0 +1,178 @@
+use crate::watcher::*;
+use std::{
+    hash::Hash, marker::Send, sync::{mpsc, Arc, Mutex}, thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use serde::{Deserialize, Serialize};
+
+/// Intervals a tree that keeps changing goes without being rendered
+const MAX_RENDER_DELAY: u32 = 5;
+
+/// Keeps the build_tree() output of a watched directory up to date on a
+/// background thread, for TUI loops that redraw every frame.
+///
+/// The tree is walked once, then kept in step by applying the events of
+/// Watcher::watch(). Changes are rendered once no event has arrived for
+/// `interval`, and at least every 5 intervals while events keep coming.
+pub struct LiveTreeRenderer {
+    lines: Arc<Mutex<Vec<String>>>,
+    error: Arc<Mutex<Option<WatcherError>>>,
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl LiveTreeRenderer {
+    /// Walks and starts watching `watcher` on a background thread, returning
+    /// once its tree has been rendered for the first time
+    pub fn new<K, V>(
+        mut watcher: Watcher<K, V>, interval: Duration
+    ) -> Result<Self, WatcherError>
+    where
+        K: Hash + Eq + Clone + Send + 'static + Serialize + for<'de> Deserialize<'de>,
+        V: Clone + Serialize + Send + 'static + for<'de> Deserialize<'de>
+    {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let error = Arc::new(Mutex::new(None));
+        let (started, start) = mpsc::channel::<Result<(), WatcherError>>();
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let (shared, failed) = (Arc::clone(&lines), Arc::clone(&error));
+        let handle = std::thread::spawn(move || {
+            let mut stream = match watcher.watch() {
+                Ok(stream) => stream,
+                Err(e) => { _ = started.send(Err(e)); return; },
+            };
+            set(&shared, stream.dir_info().build_tree());
+            _ = started.send(Ok(()));
+
+            let mut pending: Option<Instant> = None;
+            //Stops on cancel() or when the renderer is dropped
+            while let Err(mpsc::TryRecvError::Empty) = stopped.try_recv() {
+                let events = match stream.wait(interval) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        // Whatever was applied before the error is rendered
+                        // with the next change
+                        set(&failed, Some(e));
+                        pending.get_or_insert_with(Instant::now);
+                        if !matches!(stopped.recv_timeout(interval), Err(mpsc::RecvTimeoutError::Timeout)) {
+                            break;
+                        }
+                        continue;
+                    },
+                };
+
+                let since = match (events.is_empty(), pending) {
+                    (false, None) => { pending = Some(Instant::now()); continue; },
+                    (_, Some(since)) => since,
+                    (true, None) => continue,
+                };
+                if events.is_empty() || since.elapsed() >= interval * MAX_RENDER_DELAY {
+                    set(&shared, stream.dir_info().build_tree());
+                    pending = None;
+                }
+            }
+        });
+
+        return match start.recv() {
+            Ok(Ok(())) => Ok(Self { lines, error, stop: Some(stop), handle: Some(handle) }),
+            Ok(Err(e)) => Err(e),
+            // The thread panicked before it could report
+            Err(_) => Err(WatcherError::IOError(std::io::Error::other("renderer thread panicked"))),
+        };
+    }
+
+    /// The most recently rendered tree
+    pub fn current_lines(&self) -> Vec<String> {
+        return match self.lines.lock() {
+            Ok(lines) => lines.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+    }
+
+    /// Takes the latest error hit while reading or applying events. The
+    /// renderer keeps watching after one, but the tree may have missed the
+    /// change that caused it.
+    pub fn take_error(&self) -> Option<WatcherError> {
+        return match self.error.lock() {
+            Ok(mut error) => error.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+    }
+
+    pub fn is_running(&self) -> bool {
+        return self.handle.as_ref().is_some_and(|h| !h.is_finished());
+    }
+
+    /// Stops watching and waits for the background thread to exit; the last
+    /// rendered lines stay available
+    pub fn cancel(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() { let _ = handle.join(); }
+    }
+}
+
+impl Drop for LiveTreeRenderer {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+fn set<T>(shared: &Mutex<T>, value: T) {
+    match shared.lock() {
+        Ok(mut current) => *current = value,
+        Err(poisoned) => *poisoned.into_inner() = value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::N;
+
+    /// Polls current_lines() until one of them contains `name` or 5s pass
+    fn wait_for(renderer: &LiveTreeRenderer, name: &str) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if renderer.current_lines().iter().any(|line| line.contains(name)) { return true; }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        return false;
+    }
+
+    #[test]
+    fn renders_changes_from_events() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("before"), "").unwrap();
+        let watcher: Watcher<N, N> = Watcher::new(dir.path().to_str().unwrap()).unwrap();
+        let mut renderer = LiveTreeRenderer::new(watcher, Duration::from_millis(20)).unwrap();
+        assert!(wait_for(&renderer, "before"));
+
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub").join("after"), "").unwrap();
+        assert!(wait_for(&renderer, "after"), "{:?}", renderer.current_lines());
+        assert!(renderer.take_error().is_none());
+
+        renderer.cancel();
+        assert!(!renderer.is_running());
+    }
+
+    #[test]
+    fn renders_a_tree_that_never_settles() {
+        let dir = tempfile::tempdir().unwrap();
+        let watcher: Watcher<N, N> = Watcher::new(dir.path().to_str().unwrap()).unwrap();
+        let renderer = LiveTreeRenderer::new(watcher, Duration::from_millis(50)).unwrap();
+
+        // Writes every 10ms, well inside the 50ms it would take to settle
+        let busy = dir.path().join("busy");
+        std::fs::write(&busy, "").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut rendered = false;
+        while !rendered && Instant::now() < deadline {
+            std::fs::write(&busy, "more").unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+            rendered = renderer.current_lines().iter().any(|line| line.contains("busy"));
+        }
+        assert!(rendered);
+    }
+}
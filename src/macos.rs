@@ -0,0 +1,223 @@
+use crate::{backend::*, inotify::Event};
+use std::{
+    collections::{BTreeSet, HashMap}, ffi::CString, io, os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf}, time::SystemTime,
+};
+use simplicio::*;
+
+/// kevents taken from the queue per kevent() call
+const EVENT_BATCH: usize = 64;
+
+/// kqueue backed watching on macOS.
+///
+/// kqueue reports changes per open file rather than per name, so a watched
+/// directory only learns that its listing changed. The backend keeps the
+/// entry names of each watched directory and lists it again on every
+/// change, reporting `Create` and `Delete` for the names that came and went
+/// or `Modify` on the directory itself when none did; a rename inside it is
+/// a `Delete` and a `Create`. Writes to a file within a directory aren't
+/// seen unless that file is watched itself, so callers needing file-level
+/// detail should stat or rescan the directory on its events. A watched path
+/// that is deleted or moved reports `DeleteSelf` or `MoveSelf`.
+pub struct KQueueBackend {
+    kq: i32,
+    /// Watches by the descriptor each one holds open
+    watches: HashMap<WatchId, KQueueWatch>,
+}
+
+struct KQueueWatch {
+    path: PathBuf,
+    /// Entry names of a watched directory, `None` for a file
+    entries: Option<BTreeSet<String>>,
+}
+
+impl KQueueBackend {
+    pub fn new() -> Result<Self, BackendError> {
+        let kq = unsafe { libc::kqueue() };
+        if kq == -1 {
+            return Err(BackendError::OSError(io::Error::last_os_error()));
+        }
+        return Ok(Self { kq, watches: HashMap::new() });
+    }
+}
+
+impl FileWatchBackend for KQueueBackend {
+    fn watch(&mut self, path: &Path, events: &[Event]) -> Result<WatchId, BackendError> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| BackendError::OSError(io::Error::other(e)))?;
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_EVTONLY | libc::O_CLOEXEC) };
+        if fd == -1 {
+            return Err(BackendError::OSError(io::Error::last_os_error()));
+        }
+
+        let change = libc::kevent {
+            ident: fd as libc::uintptr_t,
+            filter: libc::EVFILT_VNODE,
+            flags: libc::EV_ADD | libc::EV_ENABLE | libc::EV_CLEAR,
+            fflags: note_flags(events),
+            data: 0,
+            udata: std::ptr::null_mut(),
+        };
+        let added = unsafe {
+            libc::kevent(self.kq, &change, 1, std::ptr::null_mut(), 0, std::ptr::null())
+        };
+        if added == -1 {
+            let error = io::Error::last_os_error();
+            unsafe { libc::close(fd); }
+            return Err(BackendError::OSError(error));
+        }
+
+        let entries = match path.is_dir() {
+            true => Some(list_dir(path)),
+            false => None,
+        };
+        self.watches.insert(fd, KQueueWatch { path: path.to_path_buf(), entries });
+        return Ok(fd);
+    }
+
+    fn unwatch(&mut self, id: WatchId) -> Result<(), BackendError> {
+        if self.watches.remove(&id).is_none() {
+            return Err(BackendError::OSError(io::Error::from_raw_os_error(libc::EBADF)));
+        }
+        // Closing the descriptor also removes its kevent
+        unsafe { libc::close(id); }
+        return Ok(());
+    }
+
+    fn read_events(&mut self) -> Result<Vec<FsEvent>, BackendError> {
+        let zero = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        let mut changes = vec![];
+        loop {
+            let mut batch: [libc::kevent; EVENT_BATCH] = unsafe { std::mem::zeroed() };
+            let count = unsafe {
+                libc::kevent(
+                    self.kq, std::ptr::null(), 0, batch.as_mut_ptr(), EVENT_BATCH as i32, &zero)
+            };
+            if count == -1 {
+                let error = io::Error::last_os_error();
+                if error.raw_os_error() == Some(libc::EINTR) { continue; }
+                return Err(BackendError::OSError(error));
+            }
+
+            let count = count as usize;
+            changes.extend(batch[..count].iter().map(|k| (k.ident as WatchId, k.fflags)));
+            if count < EVENT_BATCH { break; }
+        }
+
+        let time = SystemTime::now();
+        let mut events = vec![];
+        for (id, fflags) in changes {
+            let Some(watch) = self.watches.get_mut(&id) else { continue; };
+            events.extend(watch.events(id, fflags, time));
+        }
+        return Ok(events);
+    }
+}
+
+impl Drop for KQueueBackend {
+    fn drop(&mut self) {
+        for id in self.watches.keys() {
+            unsafe { libc::close(*id); }
+        }
+        unsafe { libc::close(self.kq); }
+    }
+}
+
+impl KQueueWatch {
+    /// Turns the flags of one kevent into events, listing a directory again
+    /// to find the names that changed
+    fn events(&mut self, id: WatchId, fflags: u32, time: SystemTime) -> Vec<FsEvent> {
+        let mut events = vec![];
+        if fflags & (libc::NOTE_WRITE | libc::NOTE_EXTEND) != 0 {
+            match &mut self.entries {
+                Some(entries) => {
+                    let current = list_dir(&self.path);
+                    for name in current.difference(entries) {
+                        events.push(fs_event(id, &self.path, name, Event::Create, time));
+                    }
+                    for name in entries.difference(&current) {
+                        events.push(fs_event(id, &self.path, name, Event::Delete, time));
+                    }
+                    if events.is_empty() {
+                        events.push(fs_event(id, &self.path, "", Event::Modify, time));
+                    }
+                    *entries = current;
+                },
+                None => events.push(fs_event(id, &self.path, "", Event::Modify, time)),
+            }
+        }
+        if fflags & libc::NOTE_ATTRIB != 0 {
+            events.push(fs_event(id, &self.path, "", Event::Attrib, time));
+        }
+        if fflags & libc::NOTE_DELETE != 0 {
+            events.push(fs_event(id, &self.path, "", Event::DeleteSelf, time));
+        }
+        if fflags & libc::NOTE_RENAME != 0 {
+            events.push(fs_event(id, &self.path, "", Event::MoveSelf, time));
+        }
+        return events;
+    }
+}
+
+fn fs_event(id: WatchId, dir: &Path, name: &str, event: Event, time: SystemTime) -> FsEvent {
+    let path = match name.is_empty() {
+        true => dir.to_path_buf(),
+        false => dir.join(name),
+    };
+    return FsEvent { wd: id, event, name: s!(name), path, from: None, time };
+}
+
+/// Names in the directory at `path`, empty once it can't be read
+fn list_dir(path: &Path) -> BTreeSet<String> {
+    let Ok(entries) = std::fs::read_dir(path) else { return BTreeSet::new(); };
+    return entries.flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+}
+
+/// The vnode changes to ask kqueue for, all of them when `events` is empty
+fn note_flags(events: &[Event]) -> u32 {
+    let writes = libc::NOTE_WRITE | libc::NOTE_EXTEND;
+    if events.is_empty() {
+        return writes | libc::NOTE_ATTRIB | libc::NOTE_DELETE | libc::NOTE_RENAME;
+    }
+
+    return events.iter().fold(0, |flags, event| flags | match event {
+        Event::Create | Event::Delete | Event::MovedFrom | Event::MovedTo
+        | Event::Renamed | Event::Modify | Event::CloseWrite => writes,
+        Event::Attrib => libc::NOTE_ATTRIB,
+        Event::DeleteSelf => libc::NOTE_DELETE,
+        Event::MoveSelf => libc::NOTE_RENAME,
+        _ => 0,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (event name, entry name) of every event read so far
+    fn read(backend: &mut KQueueBackend) -> Vec<(&'static str, String)> {
+        return backend.read_events().unwrap().into_iter()
+            .map(|event| (event.event.name(), event.name))
+            .collect();
+    }
+
+    #[test]
+    fn reports_creates_deletes_and_renames_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut backend = KQueueBackend::new().unwrap();
+        backend.watch(dir.path(), &[]).unwrap();
+
+        std::fs::write(dir.path().join("a"), "").unwrap();
+        assert_eq!(read(&mut backend), vec![("create", s!("a"))]);
+
+        std::fs::rename(dir.path().join("a"), dir.path().join("b")).unwrap();
+        let events = read(&mut backend);
+        assert!(events.contains(&("delete", s!("a"))));
+        assert!(events.contains(&("create", s!("b"))));
+
+        std::fs::remove_file(dir.path().join("b")).unwrap();
+        assert_eq!(read(&mut backend), vec![("delete", s!("b"))]);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::{fs_node::*, watcher::Watcher};
+use std::{collections::HashMap, hash::Hash, io, path::PathBuf, time::SystemTime};
+use serde::{Deserialize, Serialize};
+
+/// Starts every snapshot written by Watcher::save(), ahead of the format
+/// version. Snapshots from before the header was added start with the
+/// length of the directory name instead.
+const MAGIC: &[u8; 8] = b"OVERSEER";
+
+/// Bumped whenever a field is added to or removed from the bincode layout
+/// of Watcher, DirInfo or FileInfo. bincode writes fields by position and
+/// ignores `#[serde(default)]`, so each older version needs its own
+/// decoder below.
+pub(crate) const SNAPSHOT_VERSION: u32 = 1;
+
+/// `watcher` in the current snapshot format
+pub(crate) fn encode<K, V>(watcher: &Watcher<K, V>) -> io::Result<Vec<u8>>
+where
+    K: Hash + Eq + Clone + Serialize,
+    V: Clone + Serialize
+{
+    let mut data = MAGIC.to_vec();
+    data.extend(SNAPSHOT_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut data, watcher).map_err(io::Error::other)?;
+    return Ok(data);
+}
+
+/// Reads a snapshot of any known version, migrating older ones
+pub(crate) fn decode<K, V>(data: &[u8]) -> io::Result<Watcher<K, V>>
+where
+    K: Hash + Eq + Clone + Serialize + for<'de> Deserialize<'de>,
+    V: Clone + Serialize + for<'de> Deserialize<'de>
+{
+    let Some(data) = data.strip_prefix(MAGIC) else {
+        let watcher: v0::Watcher<K, V> = bincode::deserialize(data).map_err(io::Error::other)?;
+        return Ok(watcher.into());
+    };
+
+    let (version, data) = data.split_at_checked(4)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    return match u32::from_le_bytes([version[0], version[1], version[2], version[3]]) {
+        SNAPSHOT_VERSION => bincode::deserialize(data).map_err(io::Error::other),
+        version => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("snapshot format {version} is newer than this build reads"),
+        )),
+    };
+}
+
+/// The layout written before snapshots had a header
+mod v0 {
+    use super::*;
+
+    #[derive(Deserialize)]
+    pub struct Watcher<K, V> where K: Hash + Eq + Clone, V: Clone {
+        dir_name: String,
+        path: PathBuf,
+        ignore_hidden: bool,
+        ignore_list: Vec<String>,
+        dir_info: DirInfo<K, V>,
+        // Only read past: the descriptor it holds is closed by now
+        _inotify: Option<INotify>,
+    }
+
+    #[derive(Deserialize)]
+    struct INotify {
+        _id: i32,
+        _pid: i32,
+        _path: String,
+        _events: Vec<u32>,
+        _watch_ids: Vec<i32>,
+    }
+
+    #[derive(Deserialize)]
+    enum FsNode<K, V> where K: Hash + Eq + Clone, V: Clone {
+        Directory(DirInfo<K, V>),
+        File(FileInfo<K, V>),
+    }
+
+    #[derive(Deserialize)]
+    struct DirInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
+        name: String,
+        path: PathBuf,
+        last_modified: Option<SystemTime>,
+        content: Vec<FsNode<K, V>>,
+        fields: Option<HashMap<K, V>>,
+    }
+
+    #[derive(Deserialize)]
+    struct FileInfo<K, V> where K: Hash + Eq + Clone, V: Clone {
+        name: String,
+        path: PathBuf,
+        last_modified: Option<SystemTime>,
+        fields: Option<HashMap<K, V>>,
+    }
+
+    impl<K, V> From<Watcher<K, V>> for crate::watcher::Watcher<K, V>
+    where K: Hash + Eq + Clone + Serialize, V: Clone + Serialize
+    {
+        fn from(old: Watcher<K, V>) -> Self {
+            return Self {
+                dir_name: old.dir_name,
+                path: old.path,
+                ignore_hidden: old.ignore_hidden,
+                ignore_list: old.ignore_list,
+                ignore_case_insensitive: false,
+                ignore_regexes: vec![],
+                max_depth: None,
+                respect_gitignore: false,
+                follow_symlinks: false,
+                max_path_len: None,
+                max_nodes: None,
+                only_extensions: vec![],
+                exclude_extensions: vec![],
+                prune_empty_dirs: false,
+                hashing: false,
+                semaphore: None,
+                progress: None,
+                skip_errors: false,
+                dir_info: old.dir_info.into(),
+                inotify: None,
+            };
+        }
+    }
+
+    impl<K, V> From<DirInfo<K, V>> for super::DirInfo<K, V>
+    where K: Hash + Eq + Clone, V: Clone
+    {
+        fn from(old: DirInfo<K, V>) -> Self {
+            return Self {
+                name: old.name,
+                path: old.path,
+                last_modified: old.last_modified,
+                expanded: true,
+                mode: None,
+                content: old.content.into_iter().map(|node| match node {
+                    FsNode::Directory(d) => super::FsNode::Directory(d.into()),
+                    FsNode::File(f) => super::FsNode::File(f.into()),
+                }).collect(),
+                fields: old.fields,
+            };
+        }
+    }
+
+    impl<K, V> From<FileInfo<K, V>> for super::FileInfo<K, V>
+    where K: Hash + Eq + Clone, V: Clone
+    {
+        fn from(old: FileInfo<K, V>) -> Self {
+            return Self {
+                name: old.name,
+                path: old.path,
+                last_modified: old.last_modified,
+                size: None,
+                executable: false,
+                mode: None,
+                git_status: None,
+                hash: None,
+                fields: old.fields,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Written by `save()` before snapshots were versioned, for a tree at
+    /// /tmp/overseer-v0 holding `readme` and `sub/file.txt`
+    const V0: &[u8] = include_bytes!("../tests/fixtures/v0.watcher");
+
+    #[test]
+    fn loads_a_snapshot_from_before_versioning() {
+        let watcher: Watcher<N, N> = decode(V0).unwrap();
+        assert_eq!(watcher.path, PathBuf::from("/tmp/overseer-v0"));
+        assert!(watcher.ignore_hidden);
+
+        let root = &watcher.dir_info;
+        assert!(root.expanded);
+        let Some(FsNode::Directory(sub)) = root.find(&root.path.join("sub")) else { panic!() };
+        assert!(sub.expanded);
+        let Some(FsNode::File(file)) = sub.find(&sub.path.join("file.txt")) else { panic!() };
+        assert_eq!((file.size, file.executable, file.mode), (None, false, None));
+    }
+
+    #[test]
+    fn round_trips_the_current_format() {
+        let old: Watcher<N, N> = decode(V0).unwrap();
+        let data = encode(&old).unwrap();
+        assert!(data.starts_with(MAGIC));
+
+        let loaded: Watcher<N, N> = decode(&data).unwrap();
+        assert_eq!(loaded.dir_info, old.dir_info);
+        assert_eq!(loaded.ignore_list, old.ignore_list);
+    }
+
+    #[test]
+    fn rejects_a_newer_format() {
+        let mut data = MAGIC.to_vec();
+        data.extend((SNAPSHOT_VERSION + 1).to_le_bytes());
+        let error = decode::<N, N>(&data).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
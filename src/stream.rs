@@ -0,0 +1,177 @@
+use crate::{backend::*, fs_node::*, inotify::*, watcher::*};
+use std::{hash::Hash, marker::Send, path::Path, time::Duration};
+use serde::{Deserialize, Serialize};
+
+/// Keeps a Watcher's `dir_info` in step with inotify events on its tree,
+/// returned by Watcher::watch().
+///
+/// Events are applied as they are read by poll() or wait(): created entries
+/// are added, deleted ones removed and modified ones get their new mtime
+/// and size. A rename within the tree moves the entry with its content;
+/// one half of a move alone counts as a delete or a create. New entries
+/// pass the watcher's ignore settings, except `.gitignore` files. When the
+/// inotify queue overflows and events are lost, the whole tree is walked
+/// again.
+pub struct EventStream<'a, K, V>
+where
+    K: Hash + Eq + Clone + Serialize,
+    V: Clone + Serialize
+{
+    watcher: &'a mut Watcher<K, V>,
+    backend: INotifyBackend,
+}
+
+impl<'a, K, V> EventStream<'a, K, V>
+where
+    K: Hash + Eq + Clone + Send + 'static + Serialize + for<'de> Deserialize<'de>,
+    V: Clone + Serialize + Send + 'static + for<'de> Deserialize<'de>
+{
+    pub(crate) fn new(watcher: &'a mut Watcher<K, V>) -> Result<Self, WatcherError> {
+        watcher.walk()?;
+
+        let mut stream = Self { watcher, backend: INotifyBackend::new()? };
+        let root = stream.watcher.path.clone();
+        stream.watch_tree(&root)?;
+        return Ok(stream);
+    }
+
+    /// The watcher's tree, as of the last applied event
+    pub fn dir_info(&self) -> &DirInfo<K, V> {
+        return &self.watcher.dir_info;
+    }
+
+    /// Applies the events queued so far without blocking and returns them
+    pub fn poll(&mut self) -> Result<Vec<FsEvent>, WatcherError> {
+        let events = self.backend.read_events()?;
+        for event in events.iter() { self.apply(event)?; }
+        return Ok(events);
+    }
+
+    /// poll() that first blocks until events arrive or `timeout` passes
+    pub fn wait(&mut self, timeout: Duration) -> Result<Vec<FsEvent>, WatcherError> {
+        self.backend.wait(timeout)?;
+        return self.poll();
+    }
+
+    /// Watches `path` and every directory under it in the tree
+    fn watch_tree(&mut self, path: &Path) -> Result<(), WatcherError> {
+        self.backend.watch(path, DEFAULT_EVENTS)?;
+
+        let dir = match path == self.watcher.path {
+            true => &self.watcher.dir_info,
+            false => match self.watcher.dir_info.find(path) {
+                Some(FsNode::Directory(d)) => d,
+                _ => return Ok(()),
+            },
+        };
+        let subdirs: Vec<_> = dir.iter()
+            .filter(|node| node.is_dir())
+            .map(|node| node.path())
+            .collect();
+        for subdir in subdirs { self.backend.watch(&subdir, DEFAULT_EVENTS)?; }
+        return Ok(());
+    }
+
+    fn apply(&mut self, event: &FsEvent) -> Result<(), WatcherError> {
+        if matches!(event.event, Event::Overflow) {
+            // Events were lost, so only a fresh walk can be trusted
+            self.watcher.walk()?;
+            let root = self.watcher.path.clone();
+            return self.watch_tree(&root);
+        }
+
+        let path = &event.path;
+        if path == &self.watcher.path || !path.starts_with(&self.watcher.path) { return Ok(()); }
+
+        if let (Event::Renamed, Some(from)) = (event.event, &event.from) {
+            // Moved with its content unless the new name is skipped, even
+            // when it has since moved on again
+            let kept = !matches!(self.watcher.node_at(path), Ok(None));
+            match self.watcher.dir_info.find(from).map(|node| node.is_dir()) {
+                Some(is_dir) if kept => {
+                    self.watcher.dir_info.apply_event(event);
+                    // Re-adding the watches points them at the new paths
+                    if is_dir && path.is_dir() { return self.watch_tree(path); }
+                    return Ok(());
+                },
+                _ => { self.watcher.dir_info.remove_recursive(from); },
+            }
+        }
+
+        if matches!(event.event, Event::Create | Event::MovedTo | Event::Renamed) {
+            if self.watcher.dir_info.find(path).is_some() { return Ok(()); }
+            // Already gone again, or skipped by the ignore settings
+            let Ok(Some(node)) = self.watcher.node_at(path) else { return Ok(()); };
+            if node.is_dir() {
+                // Watched before it is walked, so anything created inside
+                // in between is either walked or reported
+                self.backend.watch(path, DEFAULT_EVENTS)?;
+                self.watcher.rescan(path)?;
+                return self.watch_tree(path);
+            }
+            // Inserted as filtered, which also keeps any content hash
+            if let Some(parent) = path.parent() { self.watcher.dir_info.insert_at(parent, node); }
+            return Ok(());
+        }
+
+        let changed = self.watcher.dir_info.apply_event(event);
+        if changed && self.watcher.hashing && matches!(event.event, Event::Modify) {
+            let hash = match self.watcher.node_at(path) {
+                Ok(Some(FsNode::File(f))) => f.hash,
+                _ => None,
+            };
+            if let Some(FsNode::File(f)) = self.watcher.dir_info.find_mut(path) { f.hash = hash; }
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{path::PathBuf, time::Instant};
+
+    #[test]
+    fn file_created_after_watch_appears_in_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        let mut watcher: Watcher<N, N> = Watcher::new(dir.path().to_str().unwrap()).unwrap();
+        let mut stream = watcher.watch().unwrap();
+
+        let file = dir.path().join("sub").join("new");
+        assert!(stream.dir_info().find(&file).is_none());
+        std::fs::write(&file, "data").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while stream.dir_info().find(&file).is_none() && Instant::now() < deadline {
+            stream.wait(Duration::from_millis(100)).unwrap();
+        }
+        assert!(matches!(stream.dir_info().find(&file), Some(FsNode::File(_))));
+    }
+
+    #[test]
+    fn an_overflow_walks_the_tree_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher: Watcher<N, N> = Watcher::new(dir.path().to_str().unwrap()).unwrap();
+        let mut stream = watcher.watch().unwrap();
+
+        // Its create events stay queued, so only the walk can add them
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub").join("file"), "").unwrap();
+        let overflow = FsEvent {
+            wd: -1, event: Event::Overflow, name: String::new(), path: PathBuf::new(), from: None,
+            time: std::time::SystemTime::now(),
+        };
+        stream.apply(&overflow).unwrap();
+        assert!(stream.dir_info().find(&dir.path().join("sub").join("file")).is_some());
+
+        // The walked directory is watched from then on
+        let later = dir.path().join("sub").join("later");
+        std::fs::write(&later, "").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while stream.dir_info().find(&later).is_none() && Instant::now() < deadline {
+            stream.wait(Duration::from_millis(100)).unwrap();
+        }
+        assert!(stream.dir_info().find(&later).is_some());
+    }
+}
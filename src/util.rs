@@ -0,0 +1,87 @@
+/// Human-readable binary size with one decimal above the byte range, e.g.
+/// `999 B`, `1.0 KiB` or `2.3 MiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 { return format!("{} B", bytes); }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    // Move up a unit rather than print `1024.0`
+    while size >= 1023.95 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    return format!("{:.1} {}", size, UNITS[unit]);
+}
+
+/// `text` with the characters HTML treats specially replaced by entities
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+/// `text` with Markdown's inline formatting characters backslash-escaped
+pub(crate) fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '`' | '[' | ']' | '<' | '>') { escaped.push('\\'); }
+        escaped.push(c);
+    }
+    return escaped;
+}
+
+/// `text` escaped for use inside a double-quoted Graphviz DOT string
+pub(crate) fn escape_dot(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_bytes_uses_one_decimal_past_the_byte_range() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+
+    #[test]
+    fn format_bytes_at_unit_boundaries() {
+        let table = [
+            (0, "0 B"),
+            (999, "999 B"),
+            (1023, "1023 B"),
+            (1024, "1.0 KiB"),
+            (1025, "1.0 KiB"),
+            (1024 * 1024 - 1, "1.0 MiB"),
+            (1048576, "1.0 MiB"),
+            (2411724, "2.3 MiB"),
+            (1073741824, "1.0 GiB"),
+            (1 << 40, "1.0 TiB"),
+            (u64::MAX, "16.0 EiB"),
+        ];
+        for (bytes, expected) in table {
+            assert_eq!(format_bytes(bytes), expected, "{bytes}");
+        }
+    }
+}
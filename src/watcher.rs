@@ -1,39 +1,135 @@
-use crate::{fs_node::*, inotify::*};
-use std::{io, hash::Hash, marker::Send, path::PathBuf, fs::Metadata};
+use crate::{backend::BackendError, fs_node::*, inotify::*, snapshot};
+use std::{
+    io, hash::Hash, marker::{PhantomData, Send}, path::{Component, Path, PathBuf}, fs::Metadata, 
+    collections::{HashMap, HashSet}, sync::{atomic::{self, AtomicUsize}, Arc, Mutex}, 
+    cmp::Ordering, time::{Duration, Instant, SystemTime},
+};
 use serde::{Deserialize, Serialize};
 use async_recursion::async_recursion;
 use simplicio::*;
-use tokio::fs;
+use tokio::{fs, sync::Semaphore, task::JoinSet};
+use glob::Pattern;
+use regex::Regex;
+use ignore::{gitignore::Gitignore, Match};
 #[cfg(target_os = "windows")]
 use std::os::windows::fs::MetadataExt;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(target_os = "linux")]
+use crate::{backend::{FileWatchBackend, FsEvent, INotifyBackend}, stream::EventStream};
+#[cfg(target_os = "linux")]
+use std::{sync::atomic::AtomicBool, thread::JoinHandle};
+
+/// Name of the snapshot file written by `save` and read by `load`
+pub const SNAPSHOT_FILE: &str = ".watcher";
 
 #[derive(Debug)]
 pub enum WatcherError {
     PathDoesNotExist,
     NotADirectory,
     InvalidDirectoryName,
+    PathOutsideRoot,
+    InvalidPattern(regex::Error),
+    /// A walk found more entries than Watcher::max_nodes() allows
+    NodeLimitExceeded,
     IOError(io::Error),
     NodeError(FsNodeError),
 }
 
+impl WatcherError {
+    fn into_io(self) -> io::Error {
+        match self {
+            WatcherError::IOError(e) => e,
+            e => io::Error::other(e.to_string()),
+        }
+    }
+}
+
 impl std::fmt::Display for WatcherError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             WatcherError::PathDoesNotExist => write!(f, "Path does not exist"),
             WatcherError::NotADirectory => write!(f, "The path is not a directory"),
             WatcherError::InvalidDirectoryName => write!(f, "Invalid directory name"),
+            WatcherError::PathOutsideRoot => write!(f, "The path is not under the watched root"),
+            WatcherError::InvalidPattern(e) => write!(f, "Invalid ignore pattern: {}", e),
+            WatcherError::NodeLimitExceeded => write!(f, "The walk exceeded its node limit"),
             WatcherError::IOError(e) => write!(f, "{}", e),
             WatcherError::NodeError(e) => write!(f, "{}", e),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl From<io::Error> for WatcherError {
+    fn from(error: io::Error) -> Self {
+        WatcherError::IOError(error)
+    }
+}
+
+impl From<BackendError> for WatcherError {
+    fn from(error: BackendError) -> Self {
+        match error {
+            BackendError::OSError(e) => WatcherError::IOError(e),
+            e => WatcherError::IOError(io::Error::other(e.to_string())),
+        }
+    }
+}
+
+impl From<FsNodeError> for WatcherError {
+    fn from(error: FsNodeError) -> Self {
+        WatcherError::NodeError(error)
+    }
+}
+
+impl std::error::Error for WatcherError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WatcherError::InvalidPattern(e) => Some(e),
+            WatcherError::IOError(e) => Some(e),
+            WatcherError::NodeError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A walked node, or the path that couldn't be read and why
+pub type EntryResult<K, V> = Result<FsNode<K, V>, (PathBuf, io::Error)>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Watcher<K: Hash + Eq + Clone + Serialize, V: Clone + Serialize> {
     pub dir_name: String,
     pub path: PathBuf,
     pub ignore_hidden: bool,
     pub ignore_list: Vec<String>,
+    #[serde(default)]
+    pub ignore_case_insensitive: bool,
+    /// Matched against entry names; saved as their source patterns
+    #[serde(default, with = "regex_list")]
+    pub ignore_regexes: Vec<Regex>,
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    #[serde(default)]
+    pub respect_gitignore: bool,
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    #[serde(default)]
+    pub max_path_len: Option<usize>,
+    #[serde(default)]
+    pub max_nodes: Option<usize>,
+    #[serde(default)]
+    pub only_extensions: Vec<String>,
+    #[serde(default)]
+    pub exclude_extensions: Vec<String>,
+    #[serde(default)]
+    pub prune_empty_dirs: bool,
+    #[serde(default)]
+    pub hashing: bool,
+    #[serde(skip)]
+    pub semaphore: Option<Arc<Semaphore>>,
+    #[serde(skip)]
+    pub progress: Option<ProgressHook>,
+    #[serde(skip)]
+    pub skip_errors: bool,
     pub dir_info: DirInfo<K, V>,
     #[allow(private_interfaces)]
     pub inotify: Option<INotify>,
@@ -61,14 +157,28 @@ where
         };
 
         let dir_info: DirInfo<K, V> = DirInfo::new(
-            &s!(path.display()), None, vec![], None
-        ).map_err(|e| WatcherError::NodeError(e))?;
+            &s!(path.display()), None, true, vec![], None
+        )?;
 
         Ok(Self {
             dir_name,
             path,
             ignore_hidden: true,
             ignore_list: vec![],
+            ignore_case_insensitive: false,
+            ignore_regexes: vec![],
+            max_depth: None,
+            respect_gitignore: false,
+            follow_symlinks: false,
+            max_path_len: None,
+            max_nodes: None,
+            only_extensions: vec![],
+            exclude_extensions: vec![],
+            prune_empty_dirs: false,
+            hashing: false,
+            semaphore: None,
+            progress: None,
+            skip_errors: false,
             dir_info,
             inotify: None,
         })
@@ -93,14 +203,28 @@ where
         };
 
         let dir_info: DirInfo<K, V> = DirInfo::new(
-            &s!(path.display()), None, vec![], None
-        ).map_err(|e| WatcherError::NodeError(e))?;
+            &s!(path.display()), None, true, vec![], None
+        )?;
 
         Ok(Self {
             dir_name,
             path,
             ignore_hidden,
             ignore_list,
+            ignore_case_insensitive: false,
+            ignore_regexes: vec![],
+            max_depth: None,
+            respect_gitignore: false,
+            follow_symlinks: false,
+            max_path_len: None,
+            max_nodes: None,
+            only_extensions: vec![],
+            exclude_extensions: vec![],
+            prune_empty_dirs: false,
+            hashing: false,
+            semaphore: None,
+            progress: None,
+            skip_errors: false,
             dir_info,
             inotify: None,
         })
@@ -127,6 +251,20 @@ where
             path,
             ignore_hidden: true,
             ignore_list: vec![],
+            ignore_case_insensitive: false,
+            ignore_regexes: vec![],
+            max_depth: None,
+            respect_gitignore: false,
+            follow_symlinks: false,
+            max_path_len: None,
+            max_nodes: None,
+            only_extensions: vec![],
+            exclude_extensions: vec![],
+            prune_empty_dirs: false,
+            hashing: false,
+            semaphore: None,
+            progress: None,
+            skip_errors: false,
             dir_info,
             inotify: None,
         })
@@ -136,18 +274,193 @@ where
         return s!(self.path.display());
     }
 
+    /// Clears the ignore list and every ignore regex
     pub fn ignore_reset(&mut self) -> &mut Watcher<K, V> {
         self.ignore_list = vec![];
+        self.ignore_regexes = vec![];
         return self;
     }
 
+    /// Entries containing `*`, `?` or `[` are treated as glob patterns and
+    /// matched against both the entry name and its path relative to the
+    /// watch root; anything else must match exactly
     pub fn add_ignore(&mut self, item: &str) -> &mut Watcher<K, V> {
-        self.ignore_list.push(s!(item));
+        let item = normalize_ignore(item);
+        if !self.ignore_list.contains(&item) { self.ignore_list.push(item); }
+        return self;
+    }
+
+    /// Ignores entries whose name matches `pattern`, checked as a regular
+    /// expression when added
+    pub fn add_ignore_regex(&mut self, pattern: &str) -> Result<&mut Watcher<K, V>, WatcherError> {
+        let regex = Regex::new(pattern).map_err(WatcherError::InvalidPattern)?;
+        if !self.ignore_regexes.iter().any(|r| r.as_str() == regex.as_str()) {
+            self.ignore_regexes.push(regex);
+        }
+        return Ok(self);
+    }
+
+    /// Compares ignore entries against names and paths regardless of case,
+    /// e.g. so `Thumbs.db` also ignores `thumbs.db`. Off by default.
+    pub fn ignore_case_insensitive(&mut self, enabled: bool) -> &mut Watcher<K, V> {
+        self.ignore_case_insensitive = enabled;
+        return self;
+    }
+
+    pub fn add_ignores(&mut self, items: &[&str]) -> &mut Watcher<K, V> {
+        for item in items { self.add_ignore(item); }
         return self;
     }
 
+    /// Adds every pattern in a .gitignore-style file, one per line,
+    /// skipping blank lines and `#` comments. Ignores can't be undone by a
+    /// later rule, so `!` negations are rejected; `\!` and `\#` start a
+    /// pattern with a literal `!` or `#`. Valid patterns are added even
+    /// when others fail; the invalid ones are listed in the returned error.
+    pub fn load_ignores(&mut self, path: &Path) -> io::Result<&mut Watcher<K, V>> {
+        let data = std::fs::read_to_string(path)?;
+        let mut invalid = vec![];
+
+        for line in data.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            if line.starts_with('!') {
+                invalid.push(line);
+                continue;
+            }
+            let line = line.strip_prefix('\\').filter(|l| l.starts_with(['!', '#'])).unwrap_or(line);
+            // Directory markers don't matter when matching by name
+            let item = line.trim_end_matches('/');
+            if is_glob(item) && Pattern::new(item).is_err() {
+                invalid.push(item);
+                continue;
+            }
+            self.add_ignore(item);
+        }
+
+        if !invalid.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid ignore patterns: {}", invalid.join(", ")),
+            ));
+        }
+
+        return Ok(self);
+    }
+
     pub fn remove_ignore(&mut self, item: &str) -> &mut Watcher<K, V> {
-        self.ignore_list.retain(|i| i != item);
+        let item = normalize_ignore(item);
+        self.ignore_list.retain(|i| *i != item);
+        return self;
+    }
+
+    /// Normalizes every ignore entry and drops repeats, keeping the first
+    /// occurrence of each in place
+    pub fn dedup_ignores(&mut self) -> &mut Watcher<K, V> {
+        let mut seen = HashSet::new();
+        self.ignore_list = self.ignore_list.iter()
+            .map(|i| normalize_ignore(i))
+            .filter(|i| seen.insert(i.clone()))
+            .collect();
+        return self;
+    }
+
+    /// Stops descending below `depth` levels; directories at the limit are
+    /// kept with no content. A depth of 0 walks only the root itself.
+    pub fn max_depth(&mut self, depth: usize) -> &mut Watcher<K, V> {
+        self.max_depth = Some(depth);
+        return self;
+    }
+
+    /// Skips anything excluded by `.gitignore` files found while walking,
+    /// applying nested files on top of their parents
+    pub fn respect_gitignore(&mut self, enabled: bool) -> &mut Watcher<K, V> {
+        self.respect_gitignore = enabled;
+        return self;
+    }
+
+    /// Descends into symlinked directories. A link is not followed into a
+    /// directory that has already been walked, which also stops cycles.
+    /// When disabled, symlinks are listed as FsNode::Symlink.
+    pub fn follow_symlinks(&mut self, enabled: bool) -> &mut Watcher<K, V> {
+        self.follow_symlinks = enabled;
+        return self;
+    }
+
+    /// Walks only files with one of these extensions; directories are still
+    /// descended into. Matching ignores case and a leading dot.
+    pub fn only_extensions(&mut self, exts: &[&str]) -> &mut Watcher<K, V> {
+        self.only_extensions = exts.iter().map(|ext| normalize_extension(ext)).collect();
+        return self;
+    }
+
+    /// Skips files with any of these extensions, applied after
+    /// only_extensions(). Matching ignores case and a leading dot.
+    pub fn exclude_extensions(&mut self, exts: &[&str]) -> &mut Watcher<K, V> {
+        self.exclude_extensions = exts.iter().map(|ext| normalize_extension(ext)).collect();
+        return self;
+    }
+
+    /// Drops directories with no files anywhere beneath them once a walk
+    /// finishes, see DirInfo::prune_empty()
+    pub fn prune_empty_dirs(&mut self, enabled: bool) -> &mut Watcher<K, V> {
+        self.prune_empty_dirs = enabled;
+        return self;
+    }
+
+    /// Reads every walked file and stores a hex digest of its contents in
+    /// FileInfo::hash: BLAKE3 with the `content-hash` feature, or SHA-256
+    /// with the `sha256` feature. Without either, hashing walks fail with
+    /// an `Unsupported` IOError.
+    pub fn with_hashing(&mut self, enabled: bool) -> &mut Watcher<K, V> {
+        self.hashing = enabled;
+        return self;
+    }
+
+    /// Flags names or full paths longer than `len` characters, reported by
+    /// long_paths() once walked
+    pub fn max_path_len(&mut self, len: usize) -> &mut Watcher<K, V> {
+        self.max_path_len = Some(len);
+        return self;
+    }
+
+    /// Fails walks with NodeLimitExceeded once they find more than `limit`
+    /// entries, not counting the root; the partial tree is discarded
+    pub fn max_nodes(&mut self, limit: usize) -> &mut Watcher<K, V> {
+        self.max_nodes = Some(limit);
+        return self;
+    }
+
+    /// Skips entries below the root that can't be read instead of failing
+    /// the walk, counting them in WalkStats::errors. Going over max_nodes()
+    /// still fails. Not saved with the snapshot.
+    pub fn skip_errors(&mut self, enabled: bool) -> &mut Watcher<K, V> {
+        self.skip_errors = enabled;
+        return self;
+    }
+
+    /// Shares a semaphore with other watchers so that concurrent walks
+    /// respect one budget of open directory handles
+    pub fn share_semaphore(&mut self, semaphore: Arc<Semaphore>) -> &mut Watcher<K, V> {
+        self.semaphore = Some(semaphore);
+        return self;
+    }
+
+    /// Caps how many directories this watcher's walks hold open at once,
+    /// which also bounds how many are read concurrently. Defaults to
+    /// DEFAULT_OPEN_DIRS.
+    pub fn max_open_dirs(&mut self, limit: usize) -> &mut Watcher<K, V> {
+        self.semaphore = Some(Arc::new(Semaphore::new(limit.max(1))));
+        return self;
+    }
+
+    /// Calls `hook` with the running count of walked entries every
+    /// PROGRESS_INTERVAL entries, and once more with the total when the walk
+    /// finishes
+    pub fn on_progress(
+        &mut self, hook: impl Fn(usize) + Send + Sync + 'static
+    ) -> &mut Watcher<K, V> {
+        self.progress = Some(ProgressHook(Arc::new(hook)));
         return self;
     }
 
@@ -157,189 +470,1364 @@ where
     }
 
     pub fn walk(&mut self) -> Result<&mut Watcher<K, V>, WatcherError> {
-        let dir_path = self.path.clone();
-        let ignore_hidden = self.ignore_hidden;
-        let ignore_list = self.ignore_list.clone();
+        let runtime = tokio::runtime::Runtime::new()?;
+
+        self.dir_info = runtime.block_on(self.walk_async_inner())?;
+        return Ok(self);
+    }
+
+    /// walk() for callers already running inside a tokio runtime
+    pub async fn walk_async(&mut self) -> Result<&mut Watcher<K, V>, WatcherError> {
+        self.dir_info = self.walk_async_inner().await?;
+        return Ok(self);
+    }
+
+    /// walk() that also counts what it found and times the recursion.
+    /// With skip_errors() set, `errors` counts the entries that couldn't be
+    /// read; otherwise the first of them fails the walk.
+    pub fn walk_with_stats(&mut self) -> Result<WalkStats, WatcherError> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let config = Arc::new(self.walk_config());
+
+        let start = Instant::now();
+        let mut dir_info = runtime.block_on(dir_recurse_async(&self.path, &config, 0, &[]))?;
+        let duration = start.elapsed();
+        if self.prune_empty_dirs { dir_info.prune_empty(); }
+        config.finish();
+
+        let (files, dirs) = dir_info.counts(false);
+        let stats = WalkStats {
+            files,
+            dirs,
+            total_bytes: dir_info.total_size(),
+            duration,
+            errors: config.errors.load(atomic::Ordering::Relaxed),
+        };
+        self.dir_info = dir_info;
+        return Ok(stats);
+    }
+
+    /// walk() that hands each file and directory to `callback` as it is
+    /// found, parents before their children and siblings in name order.
+    /// Directories are passed before their content is read, so they are
+    /// always empty. The root itself is not passed.
+    pub fn walk_streaming<F: FnMut(&FsNode<K, V>)>(
+        &mut self, mut callback: F
+    ) -> Result<&mut Watcher<K, V>, WatcherError> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let config = self.walk_config();
+        self.dir_info = runtime.block_on(stream_recurse_async(
+            dir_shell(&self.path)?, &config, 0, &[], &mut callback, true
+        ))?;
+        if self.prune_empty_dirs { self.dir_info.prune_empty(); }
+        config.finish();
+        return Ok(self);
+    }
+
+    /// walk_streaming() without keeping the tree, for trees too large to
+    /// hold in memory; `dir_info` is left untouched
+    pub fn stream<F: FnMut(&FsNode<K, V>)>(&self, mut callback: F) -> Result<(), WatcherError> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let config = self.walk_config();
+        runtime.block_on(stream_recurse_async(
+            dir_shell(&self.path)?, &config, 0, &[], &mut callback, false
+        ))?;
+        config.finish();
+        return Ok(());
+    }
+
+    /// Re-walks only the directory at `path` and splices it into `dir_info`,
+    /// keeping the `expanded` flags of directories that are still there.
+    /// A directory that no longer exists is removed from the tree.
+    pub fn rescan(&mut self, path: &Path) -> Result<(), WatcherError> {
+        let relative = path.strip_prefix(&self.path)
+            .map_err(|_| WatcherError::PathOutsideRoot)?;
+        if path.exists() && !path.is_dir() { return Err(WatcherError::NotADirectory); }
 
-        let runtime = match tokio::runtime::Runtime::new() {
-            Ok(rt) => rt,
-            Err(e) => return Err(WatcherError::IOError(e)),
+        let config = Arc::new(self.walk_config());
+        // Rules from .gitignore files above `path` still apply to it
+        let mut gitignores = vec![];
+        let mut ancestor = self.path.clone();
+        for component in relative.components() {
+            gitignores = dir_gitignores(&config, &ancestor, &gitignores);
+            ancestor.push(component);
+        }
+
+        let fresh = match path.exists() {
+            true => {
+                let runtime = tokio::runtime::Runtime::new()?;
+                let depth = relative.components().count();
+                Some(runtime.block_on(dir_recurse_async(path, &config, depth, &gitignores))?)
+            },
+            false => None,
         };
+        let fresh = match (fresh, self.prune_empty_dirs) {
+            (Some(mut fresh), true) => {
+                fresh.prune_empty();
+                // An empty subdirectory goes the same way as a deleted one
+                Some(fresh).filter(|d| !d.content.is_empty() || d.path == self.path)
+            },
+            (fresh, _) => fresh,
+        };
+
+        if path == self.path {
+            let mut fresh = fresh.ok_or(WatcherError::PathDoesNotExist)?;
+            keep_expanded(&self.dir_info, &mut fresh);
+            self.dir_info = fresh;
+            return Ok(());
+        }
 
-        let dir_info = match runtime.block_on(
-            dir_recurse_async(&dir_path, ignore_hidden, &ignore_list)
-        ) {
-            Ok(content) => content,
-            Err(e) => return Err(e),
+        let parent = path.parent().ok_or(WatcherError::PathDoesNotExist)?;
+        let parent = match parent == self.path {
+            true => &mut self.dir_info,
+            false => match self.dir_info.find_mut(parent) {
+                Some(FsNode::Directory(d)) => d,
+                _ => return Err(WatcherError::PathDoesNotExist),
+            },
         };
 
-        self.dir_info = dir_info;
+        let existing = parent.content.iter().position(|node| node.path_ref() == path);
+        match (existing, fresh) {
+            (Some(index), Some(mut fresh)) => {
+                if let FsNode::Directory(old) = &parent.content[index] {
+                    keep_expanded(old, &mut fresh);
+                }
+                parent.content[index] = FsNode::Directory(fresh);
+            },
+            (None, Some(fresh)) => {
+                parent.content.push(FsNode::Directory(fresh));
+                parent.content.sort_by(|a, b| a.name_ref().cmp(b.name_ref()));
+            },
+            (Some(index), None) => { parent.content.remove(index); },
+            // Pruned as empty
+            (None, None) if path.exists() => {},
+            (None, None) => return Err(WatcherError::PathDoesNotExist),
+        }
+        return Ok(());
+    }
+
+    /// Walks the tree, then watches every walked directory so the returned
+    /// stream can keep `dir_info` in step with changes on disk
+    #[cfg(target_os = "linux")]
+    pub fn watch(&mut self) -> Result<EventStream<'_, K, V>, WatcherError> {
+        return EventStream::new(self);
+    }
+
+    /// Watches the tree on a background thread and calls `cmd` with every
+    /// batch of events that has been quiet for `debounce`, the way `entr`
+    /// or `watchexec` run a command on save. New subdirectories are watched
+    /// as they appear, and events on paths the ignore settings skip are
+    /// left out. Watching stops when the returned handle is cancelled or
+    /// dropped.
+    #[cfg(target_os = "linux")]
+    pub fn on_change<F>(&mut self, debounce: Duration, mut cmd: F) -> Result<OnChange, WatcherError>
+    where
+        F: FnMut(&[FsEvent]) + Send + 'static
+    {
+        let mut backend = INotifyBackend::new()?;
+        backend.recursive(true).debounce(debounce);
+        backend.watch(&self.path, DEFAULT_EVENTS)?;
+
+        let config = self.walk_config();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let handle = std::thread::spawn(move || {
+            return Ok(backend.listen_with(|mut events| {
+                // An overflow has no path but still means something changed
+                events.retain(|event| {
+                    matches!(event.event, Event::Overflow) || !config.skips_path(&event.path)
+                });
+                if !events.is_empty() { cmd(&events); }
+                !stopped.load(atomic::Ordering::Relaxed)
+            })?);
+        });
+
+        return Ok(OnChange { stop, handle: Some(handle) });
+    }
+
+    /// Walks without stopping at the first failure, returning every node in
+    /// pre-order (directories without their content) interleaved with the
+    /// paths that couldn't be read. Siblings come in name order. Only going
+    /// over max_nodes() fails the whole walk.
+    pub fn walk_entries(&self) -> Result<Vec<EntryResult<K, V>>, WatcherError> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let config = self.walk_config();
+
+        let mut entries = vec![];
+        runtime.block_on(entries_recurse_async(&self.path, &config, 0, &[], &mut entries))?;
+        config.finish();
+        return Ok(entries);
+    }
+
+    async fn walk_async_inner(&self) -> Result<DirInfo<K, V>, WatcherError> {
+        let config = Arc::new(self.walk_config());
+        let mut dir_info = dir_recurse_async(&self.path, &config, 0, &[]).await?;
+        if self.prune_empty_dirs { dir_info.prune_empty(); }
+        config.finish();
+        return Ok(dir_info);
+    }
+
+    fn walk_config(&self) -> WalkConfig {
+        WalkConfig {
+            root: self.path.clone(),
+            ignore_hidden: self.ignore_hidden,
+            ignore_list: self.ignore_list.iter().map(|i| match self.ignore_case_insensitive {
+                true => IgnoreRule::new(&i.to_lowercase()),
+                false => IgnoreRule::new(i),
+            }).collect(),
+            ignore_case_insensitive: self.ignore_case_insensitive,
+            ignore_regexes: self.ignore_regexes.clone(),
+            max_depth: self.max_depth,
+            respect_gitignore: self.respect_gitignore,
+            follow_symlinks: self.follow_symlinks,
+            only_extensions: self.only_extensions.clone(),
+            exclude_extensions: self.exclude_extensions.clone(),
+            hashing: self.hashing,
+            visited: Arc::new(Mutex::new(HashSet::new())),
+            semaphore: match &self.semaphore {
+                Some(semaphore) => semaphore.clone(),
+                None => Arc::new(Semaphore::new(DEFAULT_OPEN_DIRS)),
+            },
+            progress: self.progress.clone(),
+            max_nodes: self.max_nodes,
+            walked: Arc::new(AtomicUsize::new(0)),
+            skip_errors: self.skip_errors,
+            errors: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// The node a walk would record for `path`, without the content of a
+    /// directory, or `None` when the walk would skip it. `.gitignore` files
+    /// are not consulted.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn node_at(&self, path: &Path) -> io::Result<Option<FsNode<K, V>>> {
+        let config = self.walk_config();
+        let metadata = std::fs::symlink_metadata(path)?;
+        let Some(name) = path.file_name().map(|n| n.to_string_lossy().into_owned()) else {
+            return Ok(None);
+        };
+
+        if (config.ignore_hidden && is_hidden(&name, &metadata)) || config.is_ignored(&name, path) {
+            return Ok(None);
+        }
+
+        let is_dir = metadata.is_dir() || (
+            metadata.file_type().is_symlink() && config.follow_symlinks && path.is_dir()
+        );
+        if is_dir { return Ok(Some(FsNode::Directory(dir_shell(path).map_err(WatcherError::into_io)?))); }
+
+        if !config.wants_extension(path) { return Ok(None); }
+
+        let mut node = node_from_disk(path)?;
+        if let FsNode::File(f) = &mut node {
+            if config.hashing { f.hash = Some(file_hash(path)?); }
+        }
+        return Ok(Some(node));
+    }
+
+    /// Stats the directory at `path` and compares its modification time to
+    /// the cached node, returning `false` when the node isn't cached.
+    ///
+    /// A directory's mtime only changes when entries are added, removed or
+    /// renamed directly inside it, so edits to existing files or to deeper
+    /// directories are not detected here.
+    pub fn dir_is_current(&self, path: &Path) -> io::Result<bool> {
+        let modified = std::fs::metadata(path)?.modified()?;
+
+        return Ok(match self.dir_info.find_dir(path) {
+            Some(dir) => dir.last_modified == Some(modified),
+            None => false,
+        });
+    }
+
+    /// Symlinks in the walked tree that lead back into themselves or one of
+    /// their ancestors, directly or through a chain of other links in the
+    /// tree. These are what would stall a walk following symlinks. Only the
+    /// targets recorded by the walk are used; nothing is read from disk.
+    pub fn find_symlink_cycles(&self) -> Vec<PathBuf> {
+        let mut found = vec![];
+        collect_symlinks(&self.dir_info, &mut found);
+        // Compared with `.` and `..` folded away, so differently spelled
+        // paths to the same place match
+        let targets: HashMap<PathBuf, PathBuf> = found.iter()
+            .map(|(link, target)| (lexical(link), lexical(target)))
+            .collect();
+
+        let links: Vec<_> = found.iter().map(|(link, _)| (
+            link.clone(),
+            link.parent().map(lexical).unwrap_or_default(),
+            resolve_links(&lexical(link), &targets),
+        )).collect();
+        let mut cycles: Vec<PathBuf> = links.iter().filter(|(_, parent, target)| match target {
+            Some(target) => leads_back(parent, target, &links, &mut HashSet::new()),
+            None => true,
+        }).map(|(link, _, _)| link.clone()).collect();
+        cycles.sort();
+        return cycles;
+    }
+
+    /// Records each walked file's git status, colored by build_tree(). Does
+    /// nothing unless the root holds a `.git` directory; walking again
+    /// clears the annotations.
+    #[cfg(feature = "git2")]
+    pub fn annotate_git_status(&mut self) -> Result<&mut Watcher<K, V>, WatcherError> {
+        if !self.path.join(".git").is_dir() { return Ok(self); }
+
+        let repo = git2::Repository::open(&self.path)
+            .map_err(io::Error::other)?;
+        let mut options = git2::StatusOptions::new();
+        options.include_untracked(true).recurse_untracked_dirs(true);
+        let statuses = repo.statuses(Some(&mut options))
+            .map_err(io::Error::other)?;
+
+        let mut changed: HashMap<PathBuf, GitStatus> = HashMap::new();
+        for entry in statuses.iter() {
+            let Some(path) = entry.path() else { continue; };
+            let status = entry.status();
+            let git_status = if status.is_wt_new() {
+                GitStatus::Untracked
+            } else if status.is_index_new() {
+                GitStatus::Added
+            } else if status.intersects(
+                git2::Status::WT_MODIFIED | git2::Status::INDEX_MODIFIED
+                | git2::Status::WT_RENAMED | git2::Status::INDEX_RENAMED
+                | git2::Status::WT_TYPECHANGE | git2::Status::INDEX_TYPECHANGE
+            ) {
+                GitStatus::Modified
+            } else { continue; };
+            changed.insert(self.path.join(path), git_status);
+        }
+
+        annotate_git_recursion(&mut self.dir_info, &changed);
         return Ok(self);
     }
 
+    /// Files modified strictly after `reference`. Symlinks are left out,
+    /// changed or not.
+    pub fn changed_since(&self, reference: &SystemTime) -> Vec<PathBuf> {
+        return self.changed_since_with(reference, false);
+    }
+
+    /// changed_since(), also listing directories whose own mtime is newer
+    /// when `include_dirs` is set
+    pub fn changed_since_with(&self, reference: &SystemTime, include_dirs: bool) -> Vec<PathBuf> {
+        return self.dir_info.iter().filter(|node| match node {
+            FsNode::Directory(d) => include_dirs && d.cmp(reference) == Some(Ordering::Greater),
+            FsNode::File(f) => f.cmp(reference) == Some(Ordering::Greater),
+            FsNode::Symlink(_) => false,
+        }).map(|node| node.path()).collect();
+    }
+
+    /// Files and directories whose name or full path exceeds max_path_len(),
+    /// empty when no limit is set
+    pub fn long_paths(&self) -> Vec<PathBuf> {
+        let Some(len) = self.max_path_len else { return vec![]; };
+
+        return self.dir_info.iter().filter(|node| {
+            node.name().chars().count() > len
+                || node.path_ref().as_os_str().to_string_lossy().chars().count() > len
+        }).map(|node| node.path()).collect();
+    }
+
     pub fn build_tree(&self) -> Vec<String> {
         return self.dir_info.build_tree();
     }
 
+    /// build_tree() without ANSI escape codes
+    pub fn build_tree_plain(&self) -> Vec<String> {
+        return self.dir_info.build_tree_plain();
+    }
+
+    /// build_tree() rendered with the given options
+    pub fn build_tree_with(&self, config: &TreeConfig) -> Vec<String> {
+        return self.dir_info.build_tree_with(config);
+    }
+
     pub fn save(&self) -> io::Result<()> {
-        let mut path = self.path.clone();
-        path.push(".watcher");
-        let data = bincode::serialize(self)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        return self.save_to(&self.path.join(SNAPSHOT_FILE));
+    }
 
-        std::fs::write(path, data)?;
+    /// save() to an explicit file, e.g. one outside the watched tree
+    pub fn save_to(&self, file: &Path) -> io::Result<()> {
+        let data = snapshot::encode(self)?;
+
+        std::fs::write(file, data)?;
 
         return Ok(());
     }
 
     pub fn load(input: &str) -> Result<Self, WatcherError> {
-        let mut path = if input.is_empty() {
+        let path = if input.is_empty() {
             std::env::current_dir()
                 .map_err(|_| WatcherError::PathDoesNotExist)?
         } else { PathBuf::from(input) };
-        path.push(".watcher");
 
-        let data = std::fs::read(path).map_err(|e| WatcherError::IOError(e))?;
+        return Self::load_from(&path.join(SNAPSHOT_FILE));
+    }
+
+    /// load() from an explicit file written by save_to(). Snapshots saved
+    /// by older versions are migrated, with fields they lack left at their
+    /// defaults.
+    pub fn load_from(file: &Path) -> Result<Self, WatcherError> {
+        let data = std::fs::read(file)?;
 
-        let watcher = bincode::deserialize(&data)
-            .map_err(|e| WatcherError::IOError(
-                io::Error::new(io::ErrorKind::Other, e)))?;
+        let watcher = snapshot::decode(&data)?;
 
         return Ok(watcher);
     }
-}
 
+    /// save() without blocking the runtime: serialization runs on a
+    /// blocking task and the write goes through tokio::fs
+    pub async fn save_async(&self) -> io::Result<()> {
+        let watcher = self.clone();
+        let data = tokio::task::spawn_blocking(move || snapshot::encode(&watcher))
+            .await
+            .map_err(io::Error::other)??;
 
-// use std::fs;
+        fs::write(self.path.join(SNAPSHOT_FILE), data).await?;
 
-// struct FileNode {
-//     path: String,
-//     is_directory: bool,
-// }
+        return Ok(());
+    }
 
-// fn walk_file_tree(root_path: &str) {
-//     let mut stack = vec![FileNode {
-//         path: root_path.to_string(),
-//         is_directory: true,
-//     }];
+    /// load() without blocking the runtime
+    pub async fn load_async(input: &str) -> Result<Self, WatcherError> {
+        let path = if input.is_empty() {
+            std::env::current_dir()
+                .map_err(|_| WatcherError::PathDoesNotExist)?
+        } else { PathBuf::from(input) };
 
-//     while let Some(current_node) = stack.pop() {
-//         if current_node.is_directory {
-//             // Process the directory
-//             println!("Directory: {}", current_node.path);
+        let data = fs::read(path.join(SNAPSHOT_FILE)).await?;
 
-//             // Get the list of files and directories in the current directory
-//             if let Ok(contents) = fs::read_dir(&current_node.path) {
-//                 // Push directories onto the stack
-//                 for entry in contents {
-//                     if let Ok(entry) = entry {
-//                         let item_path = entry.path();
-//                         let is_directory = item_path.is_dir();
-//                         stack.push(FileNode {
-//                             path: item_path.to_string_lossy().to_string(),
-//                             is_directory,
-//                         });
-//                     }
-//                 }
-//             }
-//         } else {
-//             // Process the file
-//             println!("File: {}", current_node.path);
-//         }
-//     }
-// }
+        let watcher = tokio::task::spawn_blocking(move || snapshot::decode(&data))
+            .await
+            .map_err(io::Error::other)??;
 
-// fn main() {
-//     // Example usage
-//     walk_file_tree("/path/to/root");
-// }
+        return Ok(watcher);
+    }
 
-#[async_recursion]
-async fn dir_recurse_async<K, V>(
-    path: &PathBuf, ignore_hidden: bool, ignore_list: &[String]
-) -> Result<DirInfo<K, V>, WatcherError> 
-where 
-    K: Hash + Eq + Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static, 
-    V: Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static
-{
-    let mut content: Vec<FsNode<K, V>> = vec![];
-    
-    let mut dir = match fs::read_dir(path).await {
-        Ok(d) => d,
-        Err(e) => return Err(WatcherError::IOError(e)),
-    };
+    /// save() as human-readable JSON written to `path`
+    pub fn save_json(&self, path: &Path) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(self)
+            .map_err(io::Error::other)?;
 
-    let dir_metadata = match path.metadata() {
-        Ok(md) => md,
-        Err(e) => return Err(WatcherError::IOError(e)),
-    };
+        std::fs::write(path, data)?;
 
-    while let Some(entry) = match dir.next_entry().await {
-        Ok(entry) => entry,
-        Err(e) => return Err(WatcherError::IOError(e)),
-    } {
-        let filetype = match entry.file_type().await {
-            Ok(ft) => ft,
-            Err(e) => return Err(WatcherError::IOError(e)),
-        };
+        return Ok(());
+    }
 
-        let metadata = match entry.metadata().await {
-            Ok(m) => m,
-            Err(e) => return Err(WatcherError::IOError(e)),
-        };
+    /// load() from a JSON file written by save_json()
+    pub fn load_json(path: &Path) -> Result<Self, WatcherError> {
+        let data = std::fs::read(path)?;
 
-        let name = entry.file_name().to_string_lossy().into_owned();
+        let watcher = serde_json::from_slice(&data)
+            .map_err(io::Error::other)?;
 
-        if (ignore_hidden && is_hidden(&name, &metadata)) || ignore_list.contains(&name) {
-            continue;
-        }
+        return Ok(watcher);
+    }
 
-        let last_modified = match metadata.modified() {
-            Ok(time) => time,
-            Err(e) => return Err(WatcherError::IOError(e)),
-        };
+    /// save() as TOML written to `path`, under a top-level `[watcher]` table
+    #[cfg(feature = "toml")]
+    pub fn save_toml(&self, path: &Path) -> io::Result<()> {
+        let data = toml::to_string_pretty(&Document { watcher: self })
+            .map_err(io::Error::other)?;
 
-        content.push(match filetype.is_dir() {
-            true => {
-                let sub_path = entry.path();
-                FsNode::Directory(
-                    dir_recurse_async(&sub_path, ignore_hidden, ignore_list).await?
-                )
-            },
-            false => {
-                FsNode::File(FileInfo {
-                    name,
-                    path: entry.path(),
-                    last_modified: Some(last_modified),
-                    fields: None,
-                })
-            }
-        });
+        std::fs::write(path, data)?;
+
+        return Ok(());
     }
-    let dir_name = match path.file_name() {
-        Some(n) => {
-            match n.to_str() {
-                Some(dirn) => dirn.to_string(),
-                None => return Err(WatcherError::PathDoesNotExist),
-            }
-        },
-        None => return Err(WatcherError::PathDoesNotExist),
-    };
 
-    let last_modified = match dir_metadata.modified() {
-        Ok(lm) => Some(lm),
-        Err(e) => return Err(WatcherError::IOError(e)),
-    };
-    
-    Ok(DirInfo { 
-        name: dir_name, 
-        path: path.to_owned(), 
-        last_modified,
-        content, 
+    /// load() from a TOML file written by save_toml()
+    #[cfg(feature = "toml")]
+    pub fn load_toml(path: &Path) -> Result<Self, WatcherError> {
+        let data = std::fs::read_to_string(path)?;
+
+        let document: Document<Self> = toml::from_str(&data)
+            .map_err(io::Error::other)?;
+
+        return Ok(document.watcher);
+    }
+
+    /// save() as YAML written to `path`
+    #[cfg(feature = "yaml")]
+    pub fn save_yaml(&self, path: &Path) -> io::Result<()> {
+        let data = serde_yaml::to_string(self)
+            .map_err(io::Error::other)?;
+
+        std::fs::write(path, data)?;
+
+        return Ok(());
+    }
+
+    /// load() from a YAML file written by save_yaml()
+    #[cfg(feature = "yaml")]
+    pub fn load_yaml(path: &Path) -> Result<Self, WatcherError> {
+        let data = std::fs::read(path)?;
+
+        let watcher = serde_yaml::from_slice(&data)
+            .map_err(io::Error::other)?;
+
+        return Ok(watcher);
+    }
+}
+
+/// TOML documents must be tables, so the watcher is nested under a key
+#[cfg(feature = "toml")]
+#[derive(Serialize, Deserialize)]
+struct Document<T> {
+    watcher: T,
+}
+
+/// Totals from Watcher::walk_with_stats()
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalkStats {
+    /// Files and unfollowed symlinks
+    pub files: usize,
+    /// Directories, not counting the root
+    pub dirs: usize,
+    pub total_bytes: u64,
+    /// Time spent walking
+    pub duration: Duration,
+    /// Entries skipped because they couldn't be read
+    pub errors: usize,
+}
+
+/// Collects a Watcher's settings before creating it, so the path is only
+/// checked once in build()
+pub struct WatcherBuilder<K, V> {
+    path: String,
+    ignore_hidden: bool,
+    ignore_list: Vec<String>,
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+    // Only names the field types; the builder holds no K or V
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+// By hand, as derive would require K and V to be Clone and Debug as well
+impl<K, V> Clone for WatcherBuilder<K, V> {
+    fn clone(&self) -> Self {
+        return Self {
+            path: self.path.clone(),
+            ignore_hidden: self.ignore_hidden,
+            ignore_list: self.ignore_list.clone(),
+            max_depth: self.max_depth,
+            follow_symlinks: self.follow_symlinks,
+            _marker: PhantomData,
+        };
+    }
+}
+
+impl<K, V> std::fmt::Debug for WatcherBuilder<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f.debug_struct("WatcherBuilder")
+            .field("path", &self.path)
+            .field("ignore_hidden", &self.ignore_hidden)
+            .field("ignore_list", &self.ignore_list)
+            .field("max_depth", &self.max_depth)
+            .field("follow_symlinks", &self.follow_symlinks)
+            .finish();
+    }
+}
+
+impl<K, V> Default for WatcherBuilder<K, V> {
+    fn default() -> Self {
+        Self {
+            path: s!(),
+            ignore_hidden: true,
+            ignore_list: vec![],
+            max_depth: None,
+            follow_symlinks: false,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V> WatcherBuilder<K, V> 
+where 
+    K: Hash + Eq + Clone + Send + 'static + Serialize + for<'de> Deserialize<'de>, 
+    V: Clone + Serialize + Send + 'static + for<'de> Deserialize<'de>
+{
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Directory to watch; the current directory when left empty
+    pub fn path(&mut self, path: &str) -> &mut Self {
+        self.path = s!(path);
+        return self;
+    }
+
+    pub fn ignore_hidden(&mut self, enabled: bool) -> &mut Self {
+        self.ignore_hidden = enabled;
+        return self;
+    }
+
+    /// Same matching as Watcher::add_ignore()
+    pub fn ignore(&mut self, item: &str) -> &mut Self {
+        self.ignore_list.push(s!(item));
+        return self;
+    }
+
+    pub fn max_depth(&mut self, depth: usize) -> &mut Self {
+        self.max_depth = Some(depth);
+        return self;
+    }
+
+    pub fn follow_symlinks(&mut self, enabled: bool) -> &mut Self {
+        self.follow_symlinks = enabled;
+        return self;
+    }
+
+    /// Checks the path and creates the Watcher, not yet walked
+    pub fn build(&self) -> Result<Watcher<K, V>, WatcherError> {
+        let mut watcher = Watcher::config(&self.path, self.ignore_hidden, vec![])?;
+        for item in self.ignore_list.iter() { watcher.add_ignore(item); }
+        watcher.follow_symlinks(self.follow_symlinks);
+        watcher.max_depth = self.max_depth;
+        return Ok(watcher);
+    }
+}
+
+
+// use std::fs;
+
+// struct FileNode {
+//     path: String,
+//     is_directory: bool,
+// }
+
+// fn walk_file_tree(root_path: &str) {
+//     let mut stack = vec![FileNode {
+//         path: root_path.to_string(),
+//         is_directory: true,
+//     }];
+
+//     while let Some(current_node) = stack.pop() {
+//         if current_node.is_directory {
+//             // Process the directory
+//             println!("Directory: {}", current_node.path);
+
+//             // Get the list of files and directories in the current directory
+//             if let Ok(contents) = fs::read_dir(&current_node.path) {
+//                 // Push directories onto the stack
+//                 for entry in contents {
+//                     if let Ok(entry) = entry {
+//                         let item_path = entry.path();
+//                         let is_directory = item_path.is_dir();
+//                         stack.push(FileNode {
+//                             path: item_path.to_string_lossy().to_string(),
+//                             is_directory,
+//                         });
+//                     }
+//                 }
+//             }
+//         } else {
+//             // Process the file
+//             println!("File: {}", current_node.path);
+//         }
+//     }
+// }
+
+// fn main() {
+//     // Example usage
+//     walk_file_tree("/path/to/root");
+// }
+
+/// Directories a single walk may hold open at once when no shared
+/// semaphore has been provided
+pub const DEFAULT_OPEN_DIRS: usize = 64;
+
+/// Entries walked between calls to an on_progress() hook
+pub const PROGRESS_INTERVAL: usize = 256;
+
+/// Callback registered with Watcher::on_progress()
+#[derive(Clone)]
+pub struct ProgressHook(pub Arc<dyn Fn(usize) + Send + Sync>);
+
+impl std::fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ProgressHook")
+    }
+}
+
+/// Walk settings shared by every level of the recursion
+#[derive(Clone, Debug)]
+struct WalkConfig {
+    root: PathBuf,
+    ignore_hidden: bool,
+    ignore_list: Vec<IgnoreRule>,
+    ignore_case_insensitive: bool,
+    ignore_regexes: Vec<Regex>,
+    max_depth: Option<usize>,
+    respect_gitignore: bool,
+    follow_symlinks: bool,
+    only_extensions: Vec<String>,
+    exclude_extensions: Vec<String>,
+    hashing: bool,
+    visited: Arc<Mutex<HashSet<PathBuf>>>,
+    semaphore: Arc<Semaphore>,
+    progress: Option<ProgressHook>,
+    max_nodes: Option<usize>,
+    walked: Arc<AtomicUsize>,
+    /// Skip unreadable entries, counting them in `errors`, rather than
+    /// failing the walk
+    skip_errors: bool,
+    errors: Arc<AtomicUsize>,
+}
+
+impl WalkConfig {
+    /// Whether a file's extension passes the include and exclude lists
+    fn wants_extension(&self, path: &Path) -> bool {
+        let ext = path.extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if !self.only_extensions.is_empty() && !self.only_extensions.contains(&ext) {
+            return false;
+        }
+        return !self.exclude_extensions.contains(&ext);
+    }
+
+    /// Counts one walked entry, reporting progress every PROGRESS_INTERVAL
+    /// and failing once the count passes max_nodes
+    fn tick(&self) -> Result<(), WatcherError> {
+        if self.progress.is_none() && self.max_nodes.is_none() { return Ok(()); }
+
+        let walked = self.walked.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+        if self.max_nodes.is_some_and(|max| walked > max) {
+            return Err(WatcherError::NodeLimitExceeded);
+        }
+        if let Some(progress) = &self.progress {
+            if walked.is_multiple_of(PROGRESS_INTERVAL) { (progress.0)(walked); }
+        }
+        return Ok(());
+    }
+
+    /// Counts `error` and carries on when skipping errors, otherwise hands
+    /// it back. Hitting the node limit always fails.
+    fn tolerate(&self, error: WatcherError) -> Result<(), WatcherError> {
+        return match error {
+            WatcherError::IOError(_) | WatcherError::NodeError(_) if self.skip_errors => {
+                self.errors.fetch_add(1, atomic::Ordering::Relaxed);
+                Ok(())
+            },
+            error => Err(error),
+        };
+    }
+
+    /// Reports the final count once the walk is done
+    fn finish(&self) {
+        if let Some(progress) = &self.progress {
+            (progress.0)(self.walked.load(atomic::Ordering::Relaxed));
+        }
+    }
+
+    /// Records a directory as walked, returning `false` if its canonical
+    /// path has already been seen
+    async fn visit(&self, path: &Path) -> bool {
+        let canonical = match fs::canonicalize(path).await {
+            Ok(canonical) => canonical,
+            Err(_) => return false,
+        };
+        return match self.visited.lock() {
+            Ok(mut visited) => visited.insert(canonical),
+            Err(_) => false,
+        };
+    }
+
+    /// Descends into a symlinked directory whose target hasn't already been
+    /// walked, for walks that go through the tree in order
+    async fn follow<K, V>(&self, visit: Visit<K, V>) -> Visit<K, V>
+    where K: Hash + Eq + Clone, V: Clone
+    {
+        return match visit {
+            Visit::Link(link, _) if self.visit(&link).await => Visit::Descend(link),
+            Visit::Link(link, node) if self.wants_extension(&link) => Visit::Leaf(node),
+            Visit::Link(..) => Visit::Skip,
+            visit => visit,
+        };
+    }
+
+    /// Checks the entry's name and its path relative to the walk root
+    /// against every ignore rule, lowercased when matching ignores case,
+    /// and the name against every ignore regex
+    fn is_ignored(&self, name: &str, path: &Path) -> bool {
+        if self.ignore_regexes.iter().any(|regex| regex.is_match(name)) { return true; }
+
+        let mut relative = relative_path(&self.root, path);
+        let mut name = s!(name);
+        if self.ignore_case_insensitive {
+            relative = relative.to_lowercase();
+            name = name.to_lowercase();
+        }
+        return self.ignore_list.iter().any(|rule| match rule {
+            IgnoreRule::Exact(item) => *item == name || *item == relative,
+            IgnoreRule::Glob(pattern) => pattern.matches(&name) || pattern.matches(&relative),
+        });
+    }
+
+    /// Whether `path` or a directory it lies in below the root is hidden
+    /// or ignored, going by names alone as the path may be gone already.
+    /// Paths outside the root are skipped.
+    #[cfg(target_os = "linux")]
+    fn skips_path(&self, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else { return true; };
+        let mut current = self.root.clone();
+        for component in relative.components() {
+            current.push(component);
+            let name = component.as_os_str().to_string_lossy();
+            if self.ignore_hidden && name.starts_with('.') { return true; }
+            if self.is_ignored(&name, &current) { return true; }
+        }
+        return false;
+    }
+}
+
+/// Keeps a Watcher::on_change() callback running on its own thread until
+/// cancelled or dropped
+#[cfg(target_os = "linux")]
+pub struct OnChange {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<Result<(), WatcherError>>>,
+}
+
+#[cfg(target_os = "linux")]
+impl OnChange {
+    pub fn is_running(&self) -> bool {
+        return self.handle.as_ref().is_some_and(|h| !h.is_finished());
+    }
+
+    /// Stops watching and waits for the thread to exit, which takes up to
+    /// 100ms plus any callback still running. Returns the error that ended
+    /// the watch early, if one did.
+    pub fn cancel(&mut self) -> Result<(), WatcherError> {
+        self.stop.store(true, atomic::Ordering::Relaxed);
+        return match self.handle.take().map(|handle| handle.join()) {
+            Some(Ok(result)) => result,
+            // The callback panicked
+            Some(Err(_)) => Err(WatcherError::IOError(io::Error::other("on_change callback panicked"))),
+            None => Ok(()),
+        };
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for OnChange {
+    fn drop(&mut self) {
+        _ = self.cancel();
+    }
+}
+
+#[derive(Clone, Debug)]
+enum IgnoreRule {
+    Exact(String),
+    Glob(Pattern),
+}
+
+impl IgnoreRule {
+    /// Entries without glob metacharacters, or that fail to compile, are
+    /// matched exactly
+    fn new(item: &str) -> Self {
+        if !is_glob(item) { return IgnoreRule::Exact(s!(item)); }
+        match Pattern::new(item) {
+            Ok(pattern) => IgnoreRule::Glob(pattern),
+            Err(_) => IgnoreRule::Exact(s!(item)),
+        }
+    }
+}
+
+/// Serializes regexes as their source patterns, recompiling them on load
+mod regex_list {
+    use regex::Regex;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(regexes: &[Regex], serializer: S) -> Result<S::Ok, S::Error> {
+        return serializer.collect_seq(regexes.iter().map(|regex| regex.as_str()));
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Regex>, D::Error> {
+        return Vec::<String>::deserialize(deserializer)?.iter()
+            .map(|pattern| Regex::new(pattern).map_err(D::Error::custom))
+            .collect();
+    }
+}
+
+/// Ignore entries compare equal once surrounding whitespace and trailing
+/// directory markers are removed
+fn normalize_ignore(item: &str) -> String {
+    return s!(item.trim().trim_end_matches('/'));
+}
+
+/// Lowercase with no leading dot, as compared against file extensions
+fn normalize_extension(ext: &str) -> String {
+    return ext.trim_start_matches('.').to_lowercase();
+}
+
+fn is_glob(item: &str) -> bool {
+    return item.contains(['*', '?', '[']);
+}
+
+/// `path` relative to `root` with `/` separators on every platform
+fn relative_path(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    return relative.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+}
+
+/// A symlinked directory found by real_dirs_async(), with the depth and
+/// .gitignore rules it would be walked with
+type PendingLink = (PathBuf, usize, Vec<Arc<Gitignore>>);
+
+/// Walks the real directories first, then follows symlinked ones in path
+/// order, so whether a link is walked or kept as a link never depends on
+/// which task finishes first. A link is only walked when its target isn't
+/// a directory walked already, and links found inside a followed one are
+/// followed in the next round.
+async fn dir_recurse_async<K, V>(
+    path: &Path, config: &Arc<WalkConfig>, depth: usize, gitignores: &[Arc<Gitignore>]
+) -> Result<DirInfo<K, V>, WatcherError> 
+where 
+    K: Hash + Eq + Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static, 
+    V: Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static
+{
+    let links = Arc::new(Mutex::new(vec![]));
+    let mut dir_info = real_dirs_async(path, config, depth, gitignores, &links).await?;
+
+    loop {
+        let mut round: Vec<PendingLink> = match links.lock() {
+            Ok(mut links) => std::mem::take(&mut *links),
+            Err(poisoned) => std::mem::take(&mut *poisoned.into_inner()),
+        };
+        if round.is_empty() { break; }
+        round.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (link, depth, gitignores) in round {
+            if !config.visit(&link).await {
+                // Left as a link, which only stays if a file would
+                if !config.wants_extension(&link) { dir_info.remove_recursive(&link); }
+                continue;
+            }
+            let linked = match real_dirs_async(&link, config, depth, &gitignores, &links).await {
+                Ok(linked) => linked,
+                Err(e) => { config.tolerate(e)?; continue; },
+            };
+            if let Some(node) = dir_info.find_mut(&link) { *node = FsNode::Directory(linked); }
+        }
+    }
+
+    Ok(dir_info)
+}
+
+/// Sibling subdirectories are walked as concurrent tasks, bounded by the
+/// config's semaphore; content is sorted by name so the result doesn't
+/// depend on which finishes first. Symlinked directories are recorded as
+/// links and queued in `links` for dir_recurse_async() to follow. The
+/// tasks still running are aborted when one fails.
+#[async_recursion]
+async fn real_dirs_async<K, V>(
+    path: &Path, config: &Arc<WalkConfig>, depth: usize, gitignores: &[Arc<Gitignore>],
+    links: &Arc<Mutex<Vec<PendingLink>>>
+) -> Result<DirInfo<K, V>, WatcherError> 
+where 
+    K: Hash + Eq + Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static, 
+    V: Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static
+{
+    let mut dir_info = dir_shell(path)?;
+
+    // Directories at the depth limit are kept, but left empty
+    if config.max_depth.is_some_and(|max| depth >= max) {
+        return Ok(dir_info);
+    }
+
+    if config.follow_symlinks { config.visit(path).await; }
+    let gitignores = dir_gitignores(config, path, gitignores);
+
+    // Dropped on an early return, which aborts the tasks
+    let mut subdirs = JoinSet::new();
+    for entry in read_entries(config, path).await? {
+        let visit = match visit_entry(config, &entry, &gitignores).await {
+            Ok(visit) => visit,
+            Err(e) => { config.tolerate(e.into())?; continue; },
+        };
+        match visit {
+            Visit::Skip => continue,
+            Visit::Descend(sub_path) => {
+                config.tick()?;
+                let (config, links) = (Arc::clone(config), Arc::clone(links));
+                let gitignores = gitignores.clone();
+                subdirs.spawn(async move {
+                    real_dirs_async(&sub_path, &config, depth + 1, &gitignores, &links).await
+                });
+            },
+            Visit::Link(link, node) => {
+                config.tick()?;
+                dir_info.content.push(node);
+                if let Ok(mut links) = links.lock() {
+                    links.push((link, depth + 1, gitignores.clone()));
+                }
+            },
+            Visit::Leaf(node) => {
+                config.tick()?;
+                dir_info.content.push(node);
+            },
+        }
+    }
+
+    while let Some(subdir) = subdirs.join_next().await {
+        match subdir.map_err(io::Error::other)? {
+            Ok(subdir) => dir_info.content.push(FsNode::Directory(subdir)),
+            Err(e) => config.tolerate(e)?,
+        }
+    }
+    dir_info.content.sort_by(|a, b| a.name_ref().cmp(b.name_ref()));
+
+    Ok(dir_info)
+}
+
+/// Like dir_recurse_async, but one directory at a time so `callback` sees
+/// nodes in a fixed order; `dir_info` comes in as its shell and is only
+/// filled in when `keep` is set
+#[async_recursion(?Send)]
+async fn stream_recurse_async<K, V>(
+    mut dir_info: DirInfo<K, V>, config: &WalkConfig, depth: usize, 
+    gitignores: &[Arc<Gitignore>], callback: &mut dyn FnMut(&FsNode<K, V>), keep: bool
+) -> Result<DirInfo<K, V>, WatcherError>
+where 
+    K: Hash + Eq + Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static, 
+    V: Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static
+{
+    if config.max_depth.is_some_and(|max| depth >= max) {
+        return Ok(dir_info);
+    }
+
+    let path = dir_info.path.clone();
+    if config.follow_symlinks { config.visit(&path).await; }
+    let gitignores = dir_gitignores(config, &path, gitignores);
+
+    let mut entries = read_entries(config, &path).await?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let visit = match visit_entry(config, &entry, &gitignores).await {
+            Ok(visit) => visit,
+            Err(e) => { config.tolerate(e.into())?; continue; },
+        };
+        let node = match config.follow(visit).await {
+            Visit::Skip | Visit::Link(..) => continue,
+            Visit::Descend(sub_path) => {
+                let shell = match dir_shell(&sub_path) {
+                    Ok(shell) => shell,
+                    Err(e) => { config.tolerate(e)?; continue; },
+                };
+                config.tick()?;
+                callback(&FsNode::Directory(shell.clone()));
+                // Left out of the tree when it can't be read, as in walk()
+                match stream_recurse_async(shell, config, depth + 1, &gitignores, callback, keep).await {
+                    Ok(subdir) => FsNode::Directory(subdir),
+                    Err(e) => { config.tolerate(e)?; continue; },
+                }
+            },
+            Visit::Leaf(node) => {
+                config.tick()?;
+                callback(&node);
+                node
+            },
+        };
+        if keep { dir_info.content.push(node); }
+    }
+
+    return Ok(dir_info);
+}
+
+/// Like dir_recurse_async, but records failures per path and carries on.
+/// Nodes are pushed in pre-order, siblings sorted by name, with
+/// directories left empty. Only the node limit stops it.
+#[async_recursion]
+async fn entries_recurse_async<K, V>(
+    path: &Path, config: &WalkConfig, depth: usize, gitignores: &[Arc<Gitignore>],
+    entries: &mut Vec<EntryResult<K, V>>
+) -> Result<(), WatcherError>
+where 
+    K: Hash + Eq + Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static, 
+    V: Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static
+{
+    if config.max_depth.is_some_and(|max| depth >= max) { return Ok(()); }
+
+    if config.follow_symlinks { config.visit(path).await; }
+    let gitignores = dir_gitignores(config, path, gitignores);
+
+    let mut dir_entries = match read_entries(config, path).await {
+        Ok(dir_entries) => dir_entries,
+        Err(e) => {
+            entries.push(Err((path.to_owned(), e)));
+            return Ok(());
+        },
+    };
+    dir_entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in dir_entries {
+        let visit = match visit_entry(config, &entry, &gitignores).await {
+            Ok(visit) => Ok(config.follow(visit).await),
+            Err(e) => Err(e),
+        };
+        match visit {
+            Ok(Visit::Skip | Visit::Link(..)) => continue,
+            Ok(Visit::Descend(sub_path)) => match dir_shell(&sub_path) {
+                Ok(dir_info) => {
+                    config.tick()?;
+                    entries.push(Ok(FsNode::Directory(dir_info)));
+                    entries_recurse_async(&sub_path, config, depth + 1, &gitignores, entries).await?;
+                },
+                Err(e) => entries.push(Err((sub_path, e.into_io()))),
+            },
+            Ok(Visit::Leaf(node)) => {
+                config.tick()?;
+                entries.push(Ok(node));
+            },
+            Err(e) => entries.push(Err((entry.path(), e))),
+        }
+    }
+    return Ok(());
+}
+
+/// What the walk does with a single directory entry
+enum Visit<K, V> where K: Hash + Eq + Clone, V: Clone {
+    Skip,
+    Descend(PathBuf),
+    /// A symlink to a directory when following symlinks, with the node it
+    /// is recorded as when not followed
+    Link(PathBuf, FsNode<K, V>),
+    /// A file, or a symlink that isn't followed
+    Leaf(FsNode<K, V>),
+}
+
+/// The directory at `path` with its own metadata but no content
+fn dir_shell<K, V>(path: &Path) -> Result<DirInfo<K, V>, WatcherError> 
+where K: Hash + Eq + Clone, V: Clone
+{
+    let dir_name = path.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or(WatcherError::PathDoesNotExist)?
+        .to_string();
+
+    let metadata = path.metadata()?;
+
+    Ok(DirInfo { 
+        name: dir_name, 
+        path: path.to_owned(), 
+        last_modified: Some(metadata.modified()?),
+        expanded: true,
+        mode: file_mode(&metadata),
+        content: vec![], 
         fields: Some(map!()), 
     })
 }
 
+/// Rules from this directory's .gitignore apply after its ancestors'
+fn dir_gitignores(
+    config: &WalkConfig, path: &Path, gitignores: &[Arc<Gitignore>]
+) -> Vec<Arc<Gitignore>> {
+    let mut gitignores = gitignores.to_vec();
+    if config.respect_gitignore {
+        let file = path.join(".gitignore");
+        if file.is_file() {
+            gitignores.push(Arc::new(Gitignore::new(file).0));
+        }
+    }
+    return gitignores;
+}
+
+/// Hold a permit only while the directory handle is open, so nested
+/// directories never wait on a permit held by an ancestor
+async fn read_entries(config: &WalkConfig, path: &Path) -> io::Result<Vec<fs::DirEntry>> {
+    let _permit = config.semaphore.acquire().await
+        .map_err(io::Error::other)?;
+
+    let mut dir = fs::read_dir(path).await?;
+    let mut entries = vec![];
+    while let Some(entry) = dir.next_entry().await? {
+        entries.push(entry);
+    }
+    return Ok(entries);
+}
+
+async fn visit_entry<K, V>(
+    config: &WalkConfig, entry: &fs::DirEntry, gitignores: &[Arc<Gitignore>]
+) -> io::Result<Visit<K, V>> 
+where K: Hash + Eq + Clone, V: Clone
+{
+    let filetype = entry.file_type().await?;
+    let metadata = entry.metadata().await?;
+    let name = entry.file_name().to_string_lossy().into_owned();
+
+    if (config.ignore_hidden && is_hidden(&name, &metadata))
+        || config.is_ignored(&name, &entry.path())
+        || is_gitignored(gitignores, &entry.path(), filetype.is_dir()) {
+        return Ok(Visit::Skip);
+    }
+
+    if filetype.is_dir() { return Ok(Visit::Descend(entry.path())); }
+
+    if filetype.is_symlink() {
+        let target = fs::read_link(entry.path()).await?;
+        let node = FsNode::Symlink(symlink_info(name, entry.path(), target, &metadata)?);
+        let to_dir = config.follow_symlinks
+            && fs::metadata(entry.path()).await.is_ok_and(|target| target.is_dir());
+        if to_dir { return Ok(Visit::Link(entry.path(), node)); }
+        if !config.wants_extension(&entry.path()) { return Ok(Visit::Skip); }
+        return Ok(Visit::Leaf(node));
+    }
+
+    if !config.wants_extension(&entry.path()) { return Ok(Visit::Skip); }
+
+    let hash = match config.hashing {
+        true => {
+            let path = entry.path();
+            Some(tokio::task::spawn_blocking(move || file_hash(&path)).await??)
+        },
+        false => None,
+    };
+
+    return Ok(Visit::Leaf(FsNode::File(file_info(name, entry.path(), &metadata, hash)?)));
+}
+
+/// The file, symlink or empty directory at `path`, with no filtering
+#[cfg(target_os = "linux")]
+pub(crate) fn node_from_disk<K, V>(path: &Path) -> io::Result<FsNode<K, V>>
+where K: Hash + Eq + Clone, V: Clone
+{
+    let metadata = std::fs::symlink_metadata(path)?;
+    if metadata.is_dir() { return Ok(FsNode::Directory(dir_shell(path).map_err(WatcherError::into_io)?)); }
+
+    let name = path.file_name()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?
+        .to_string_lossy().into_owned();
+    if metadata.file_type().is_symlink() {
+        let target = std::fs::read_link(path)?;
+        return Ok(FsNode::Symlink(symlink_info(name, path.to_owned(), target, &metadata)?));
+    }
+    return Ok(FsNode::File(file_info(name, path.to_owned(), &metadata, None)?));
+}
+
+fn file_info<K, V>(
+    name: String, path: PathBuf, metadata: &Metadata, hash: Option<String>
+) -> io::Result<FileInfo<K, V>>
+where K: Hash + Eq + Clone, V: Clone
+{
+    return Ok(FileInfo {
+        executable: is_executable(&name, metadata),
+        name,
+        path,
+        last_modified: Some(metadata.modified()?),
+        size: Some(metadata.len()),
+        mode: file_mode(metadata),
+        git_status: None,
+        hash,
+        fields: None,
+    });
+}
+
+/// `target` as read from the link, resolved against the link's directory
+/// when relative
+fn symlink_info(name: String, path: PathBuf, target: PathBuf, metadata: &Metadata) -> io::Result<SymlinkInfo> {
+    return Ok(SymlinkInfo {
+        target: match path.parent() {
+            Some(parent) if target.is_relative() => parent.join(target),
+            _ => target,
+        },
+        name,
+        path,
+        last_modified: Some(metadata.modified()?),
+    });
+}
+
+/// Copies `expanded` from `old` onto the directories of `fresh` at the same paths
+fn keep_expanded<K, V>(old: &DirInfo<K, V>, fresh: &mut DirInfo<K, V>)
+where K: Hash + Eq + Clone, V: Clone
+{
+    fresh.expanded = old.expanded;
+    for node in fresh.content.iter_mut() {
+        let FsNode::Directory(dir) = node else { continue; };
+        let previous = old.content.iter().find(|n| n.path_ref() == dir.path);
+        if let Some(FsNode::Directory(previous)) = previous { keep_expanded(previous, dir); }
+    }
+}
+
+/// Resolved symlinks in the tree as (link, canonical parent, canonical target)
+/// (link, target) of every symlink recorded in the tree
+fn collect_symlinks<K, V>(dir_info: &DirInfo<K, V>, links: &mut Vec<(PathBuf, PathBuf)>) 
+where K: Hash + Eq + Clone, V: Clone
+{
+    for node in dir_info.content.iter() {
+        match node {
+            FsNode::Directory(d) => collect_symlinks(d, links),
+            FsNode::File(_) => continue,
+            FsNode::Symlink(l) => links.push((l.path.clone(), l.target.clone())),
+        }
+    }
+}
+
+/// Where `path` ends up once every link in the tree along it is replaced
+/// by its target, or `None` when the links chain into a loop
+fn resolve_links(path: &Path, targets: &HashMap<PathBuf, PathBuf>) -> Option<PathBuf> {
+    let mut path = path.to_path_buf();
+    let mut seen = HashSet::new();
+    // The link itself is the first one replaced
+    while let Some(link) = path.ancestors().find(|p| targets.contains_key(*p)).map(Path::to_path_buf) {
+        if !seen.insert(path.clone()) { return None; }
+        let rest = path.strip_prefix(&link).unwrap_or(Path::new(""));
+        path = lexical(&targets[&link].join(rest));
+    }
+    return Some(path);
+}
+
+/// `path` with `.` and `..` components folded away, without touching disk
+fn lexical(path: &Path) -> PathBuf {
+    let mut folded = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => match folded.components().next_back() {
+                Some(Component::Normal(_)) => { folded.pop(); },
+                // Nothing above the root; a relative path keeps climbing
+                Some(Component::RootDir | Component::Prefix(_)) => {},
+                _ => folded.push(".."),
+            },
+            component => folded.push(component),
+        }
+    }
+    return folded;
+}
+
+/// Follows `target` through any links beneath it, looking for one that
+/// leads back to `origin` or one of its ancestors
+fn leads_back(
+    origin: &Path, target: &Path, links: &[(PathBuf, PathBuf, Option<PathBuf>)], 
+    seen: &mut HashSet<PathBuf>
+) -> bool {
+    if origin.starts_with(target) { return true; }
+    if !seen.insert(target.to_owned()) { return false; }
+
+    return links.iter().any(|(_, parent, next)| match next {
+        Some(next) => parent.starts_with(target) && leads_back(origin, next, links, seen),
+        None => false,
+    });
+}
+
+/// The deepest .gitignore with a matching rule decides, so a nested file
+/// can re-include what a parent excluded
+fn is_gitignored(gitignores: &[Arc<Gitignore>], path: &Path, is_dir: bool) -> bool {
+    for gitignore in gitignores.iter().rev() {
+        match gitignore.matched(path, is_dir) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => continue,
+        }
+    }
+    return false;
+}
+
 #[allow(unused_variables)]
 fn is_hidden(name: &str, metadata: &Metadata) -> bool {
     if name.starts_with('.') { return true; }
@@ -347,10 +1835,902 @@ fn is_hidden(name: &str, metadata: &Metadata) -> bool {
     {
         return (metadata.file_attributes() & 0x2) != 0;
     }
-    #[cfg(target_os = "linux")]
+    // Dot files are the only hidden entries elsewhere
+    #[cfg(not(target_os = "windows"))]
+    {
+        return false;
+    }
+}
+
+#[cfg(feature = "git2")]
+fn annotate_git_recursion<K: Hash + Eq + Clone, V: Clone>(
+    dir_info: &mut DirInfo<K, V>, changed: &HashMap<PathBuf, GitStatus>
+) {
+    for node in dir_info.content.iter_mut() {
+        match node {
+            FsNode::Directory(d) => annotate_git_recursion(d, changed),
+            FsNode::File(f) => {
+                f.git_status = Some(changed.get(&f.path).copied().unwrap_or(GitStatus::Clean));
+            },
+            FsNode::Symlink(_) => {},
+        }
+    }
+}
+
+/// Hex digest of the file's contents, read in chunks
+#[allow(unused_variables)]
+fn file_hash(path: &Path) -> io::Result<String> {
+    #[cfg(all(feature = "content-hash", not(feature = "sha256")))]
+    {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut file, &mut hasher)?;
+        return Ok(s!(hasher.finalize().to_hex()));
+    }
+    #[cfg(feature = "sha256")]
+    {
+        use sha2::{Digest, Sha256};
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut file, &mut hasher)?;
+        return Ok(format!("{:x}", hasher.finalize()));
+    }
+    #[cfg(not(any(feature = "content-hash", feature = "sha256")))]
+    {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "no content hash feature enabled"));
+    }
+}
+
+/// Permission bits on Unix, file attribute flags on Windows
+#[allow(unused_variables)]
+fn file_mode(metadata: &Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        return Some(metadata.permissions().mode());
+    }
+    #[cfg(target_os = "windows")]
+    {
+        return Some(metadata.file_attributes());
+    }
+    #[cfg(not(any(unix, target_os = "windows")))]
+    {
+        return None;
+    }
+}
+
+/// Any execute bit on Unix, a runnable extension on Windows
+#[allow(unused_variables)]
+fn is_executable(name: &str, metadata: &Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        return metadata.permissions().mode() & 0o111 != 0;
+    }
+    #[cfg(target_os = "windows")]
+    {
+        let name = name.to_lowercase();
+        return [".exe", ".bat", ".cmd", ".com"].iter().any(|ext| name.ends_with(ext));
+    }
+    #[cfg(not(any(unix, target_os = "windows")))]
     {
         return false;
     }
-    
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watcher(dir: &Path) -> Watcher<N, N> {
+        return Watcher::new(dir.to_str().unwrap()).unwrap();
+    }
+
+    /// Every path in the walked tree below the root, in walk order
+    fn paths(watcher: &Watcher<N, N>) -> Vec<PathBuf> {
+        return watcher.dir_info.iter().map(|node| node.path()).collect();
+    }
+
+    #[test]
+    fn rewalking_an_unchanged_tree_after_save_diffs_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        let mut watcher = watcher(dir.path());
+        watcher.ignore_hidden = false;
+        watcher.walk().unwrap();
+        let before = watcher.dir_info.clone();
+
+        // Both adds the snapshot and, the second time, modifies it
+        for _ in 0..2 {
+            watcher.save().unwrap();
+            watcher.walk().unwrap();
+            assert!(watcher.dir_info.find(&dir.path().join(SNAPSHOT_FILE)).is_some());
+            assert_eq!(before.diff(&watcher.dir_info), vec![]);
+        }
+    }
+
+    #[test]
+    fn walk_with_stats_counts_the_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a").join("b")).unwrap();
+        std::fs::write(dir.path().join("top"), "12345").unwrap();
+        std::fs::write(dir.path().join("a").join("b").join("deep"), "678").unwrap();
+
+        let stats = watcher(dir.path()).skip_errors(true).walk_with_stats().unwrap();
+        assert_eq!((stats.files, stats.dirs, stats.total_bytes, stats.errors), (2, 2, 8, 0));
+        assert!(stats.duration > Duration::ZERO);
+    }
+
+    #[test]
+    fn load_ignores_rejects_negations_and_keeps_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("ignores");
+        std::fs::write(&file, "# comment\n\ntarget/\n*.log\n!keep.log\n\\!bang\n[bad\n").unwrap();
+
+        let mut watcher = watcher(dir.path());
+        let error = watcher.load_ignores(&file).err().unwrap();
+        assert_eq!(error.to_string(), "Invalid ignore patterns: !keep.log, [bad");
+        assert_eq!(watcher.ignore_list, vec!["target", "*.log", "!bang"]);
+    }
+
+    #[test]
+    fn changed_since_lists_files_modified_after_the_reference() {
+        let dir = tempfile::tempdir().unwrap();
+        let reference = SystemTime::now();
+        let (earlier, later) = (reference - Duration::from_secs(60), reference + Duration::from_secs(60));
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        for (name, time) in [("old", earlier), ("same", reference), ("new", later), ("sub/new", later)] {
+            let file = std::fs::File::create(dir.path().join(name)).unwrap();
+            file.set_modified(time).unwrap();
+        }
+        std::fs::File::open(dir.path().join("sub")).unwrap().set_modified(later).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("new", dir.path().join("link")).unwrap();
+
+        let mut watcher = watcher(dir.path());
+        watcher.walk().unwrap();
+        let mut changed = watcher.changed_since(&reference);
+        changed.sort();
+        assert_eq!(changed, vec![dir.path().join("new"), dir.path().join("sub/new")]);
+        assert!(watcher.changed_since_with(&reference, true).contains(&dir.path().join("sub")));
+    }
+
+    #[test]
+    fn parallel_walk_matches_the_sequential_one_on_a_wide_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..40 {
+            let sub = dir.path().join(format!("dir{i:02}"));
+            std::fs::create_dir_all(sub.join("nested")).unwrap();
+            for j in 0..5 {
+                std::fs::write(sub.join(format!("file{j}")), "").unwrap();
+                std::fs::write(sub.join("nested").join(format!("deep{j}")), "").unwrap();
+            }
+        }
+
+        let mut parallel = watcher(dir.path());
+        parallel.max_open_dirs(4).walk().unwrap();
+        let mut sequential = watcher(dir.path());
+        sequential.walk_streaming(|_| {}).unwrap();
+
+        assert_eq!(parallel.dir_info.counts(false), (400, 80));
+        assert_eq!(paths(&parallel), paths(&sequential));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_link_to_a_walked_sibling_is_always_kept_as_a_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("real")).unwrap();
+        std::fs::write(dir.path().join("real").join("file"), "").unwrap();
+        std::fs::write(outside.path().join("linked"), "").unwrap();
+        // Sorted before the directory they point at, to race its task
+        std::os::unix::fs::symlink(dir.path().join("real"), dir.path().join("a_link")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("b_out")).unwrap();
+
+        for _ in 0..20 {
+            let mut watcher = watcher(dir.path());
+            watcher.follow_symlinks(true).walk().unwrap();
+            let tree = &watcher.dir_info;
+            assert!(matches!(tree.find(&dir.path().join("a_link")), Some(FsNode::Symlink(_))));
+            assert!(tree.find(&dir.path().join("real").join("file")).is_some());
+            assert!(tree.find(&dir.path().join("b_out").join("linked")).is_some());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn on_change_passes_batches_until_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = watcher(dir.path());
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut handle = watcher.on_change(Duration::from_millis(20), move |events| {
+            _ = sender.send(events.iter().map(|e| e.name.clone()).collect::<Vec<_>>());
+        }).unwrap();
+
+        // Hidden entries are ignored by default, so only "seen" comes through
+        std::fs::write(dir.path().join(".hidden"), "").unwrap();
+        std::fs::write(dir.path().join("seen"), "").unwrap();
+        let mut names = vec![];
+        while !names.contains(&s!("seen")) {
+            names.extend(receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+        }
+        assert!(!names.contains(&s!(".hidden")));
+
+        assert!(handle.is_running());
+        handle.cancel().unwrap();
+        assert!(!handle.is_running());
+        // The callback, and the sender it owns, went with the thread
+        assert!(receiver.recv_timeout(Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn builders_clone_and_debug_whatever_the_field_types() {
+        // Neither Clone nor Debug
+        struct Opaque;
+        let builder = WatcherBuilder::<Opaque, Opaque>::default();
+        let copy = builder.clone();
+        assert_eq!(format!("{copy:?}"), format!("{builder:?}"));
+        assert!(format!("{builder:?}").starts_with("WatcherBuilder {"));
+    }
+
+    #[test]
+    fn new_and_from_build_an_expanded_root_for_a_temp_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = watcher(dir.path());
+        assert_eq!(watcher.path, dir.path());
+        assert_eq!(watcher.dir_info.path, dir.path());
+        assert!(watcher.dir_info.expanded);
+
+        let from: Watcher<N, N> = Watcher::from(watcher.dir_info.clone()).unwrap();
+        assert_eq!(from.dir_name, watcher.dir_name);
+        assert_eq!(from.dir_info, watcher.dir_info);
+    }
+
+    #[tokio::test]
+    async fn walk_async_fills_the_tree_inside_a_runtime() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub").join("file"), "").unwrap();
+
+        let mut watcher = watcher(dir.path());
+        watcher.walk_async().await.unwrap();
+        assert_eq!(paths(&watcher), [dir.path().join("sub"), dir.path().join("sub").join("file")]);
+    }
+
+    #[test]
+    fn max_depth_leaves_dirs_at_the_limit_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let deep = dir.path().join("one").join("two").join("three");
+        std::fs::create_dir_all(&deep).unwrap();
+        std::fs::write(dir.path().join("top"), "").unwrap();
+        std::fs::write(deep.join("bottom"), "").unwrap();
+
+        let mut watcher = watcher(dir.path());
+        watcher.max_depth(1).walk().unwrap();
+        let mut found = paths(&watcher);
+        found.sort();
+        assert_eq!(found, [dir.path().join("one"), dir.path().join("top")]);
+        let Some(FsNode::Directory(one)) = watcher.dir_info.find(&dir.path().join("one")) else { panic!() };
+        assert!(one.content.is_empty());
+
+        watcher.max_depth(0).walk().unwrap();
+        assert!(watcher.dir_info.content.is_empty());
+    }
+
+    #[test]
+    fn glob_ignores_match_names_and_relative_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("target").join("debug")).unwrap();
+        for name in ["a.log", "a.txt", "exact", "exactly"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        std::fs::write(dir.path().join("target").join("debug").join("b.log"), "").unwrap();
+        std::fs::write(dir.path().join("target").join("kept"), "").unwrap();
+
+        let mut watcher = watcher(dir.path());
+        watcher.add_ignores(&["*.log", "target/de*", "exact"]).walk().unwrap();
+        let mut found = paths(&watcher);
+        found.sort();
+        assert_eq!(found, [
+            dir.path().join("a.txt"),
+            dir.path().join("exactly"),
+            dir.path().join("target"),
+            dir.path().join("target").join("kept"),
+        ]);
+    }
+
+    #[test]
+    fn nested_gitignores_can_re_include_what_a_parent_excludes() {
+        let dir = tempfile::tempdir().unwrap();
+        let keep = dir.path().join("keep");
+        std::fs::create_dir(&keep).unwrap();
+        std::fs::write(dir.path().join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(keep.join(".gitignore"), "!wanted.log\n").unwrap();
+        for file in [dir.path().join("root.log"), keep.join("wanted.log"), keep.join("other.log")] {
+            std::fs::write(file, "").unwrap();
+        }
+
+        let mut watcher = watcher(dir.path());
+        watcher.ignore_hidden = false;
+        watcher.respect_gitignore(true).walk().unwrap();
+        let mut found = paths(&watcher);
+        found.sort();
+        assert_eq!(found, [
+            dir.path().join(".gitignore"),
+            keep.clone(),
+            keep.join(".gitignore"),
+            keep.join("wanted.log"),
+        ]);
+
+        // A root .gitignore that ignores itself is left out too
+        std::fs::write(dir.path().join(".gitignore"), "*.log\n.gitignore\n").unwrap();
+        watcher.walk().unwrap();
+        assert!(watcher.dir_info.find(&dir.path().join(".gitignore")).is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn a_symlink_cycle_ends_the_walk_without_repeating_a_subtree() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        std::fs::write(outside.path().join("other"), "").unwrap();
+        // root/out -> outside, outside/back -> root
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("out")).unwrap();
+        std::os::unix::fs::symlink(dir.path(), outside.path().join("back")).unwrap();
+
+        let mut watcher = watcher(dir.path());
+        watcher.follow_symlinks(true).walk().unwrap();
+        let mut found = paths(&watcher);
+        found.sort();
+        assert_eq!(found, [
+            dir.path().join("file"),
+            dir.path().join("out"),
+            dir.path().join("out").join("back"),
+            dir.path().join("out").join("other"),
+        ]);
+        let back = watcher.dir_info.find(&dir.path().join("out").join("back"));
+        assert!(matches!(back, Some(FsNode::Symlink(_))));
+
+        // Not followed at all when off
+        watcher.follow_symlinks(false).walk().unwrap();
+        assert!(matches!(watcher.dir_info.find(&dir.path().join("out")), Some(FsNode::Symlink(_))));
+        assert_eq!(paths(&watcher).len(), 2);
+    }
+
+    #[test]
+    fn walked_files_carry_their_length() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("sized"), [0u8; 1234]).unwrap();
+
+        let mut watcher = watcher(dir.path());
+        watcher.walk().unwrap();
+        let Some(FsNode::File(file)) = watcher.dir_info.find(&dir.path().join("sized")) else { panic!() };
+        assert_eq!(file.size(), Some(1234));
+    }
+
+    #[test]
+    fn json_round_trips_the_tree_with_its_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub").join("file"), "abc").unwrap();
+        let mut watcher: Watcher<String, u32> = Watcher::new(dir.path().to_str().unwrap()).unwrap();
+        watcher.walk().unwrap();
+        watcher.dir_info.add_field(s!("root"), 1);
+        let Some(FsNode::File(file)) = watcher.dir_info.find_mut(&dir.path().join("sub").join("file")) else { panic!() };
+        file.add_field(s!("lines"), 3);
+
+        let json = dir.path().join("state.json");
+        watcher.save_json(&json).unwrap();
+        let loaded: Watcher<String, u32> = Watcher::load_json(&json).unwrap();
+        assert_eq!(loaded.dir_info, watcher.dir_info);
+        assert_eq!(loaded.path, watcher.path);
+    }
+
+    #[test]
+    fn save_to_and_load_from_use_a_file_outside_the_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let elsewhere = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        let mut watcher = watcher(dir.path());
+        watcher.walk().unwrap();
+
+        let file = elsewhere.path().join("state.bin");
+        watcher.save_to(&file).unwrap();
+        assert!(!dir.path().join(SNAPSHOT_FILE).exists());
+        let loaded: Watcher<N, N> = Watcher::load_from(&file).unwrap();
+        assert_eq!(loaded.dir_info, watcher.dir_info);
+    }
+
+    #[tokio::test]
+    async fn async_save_then_load_reproduces_the_watcher() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub").join("file"), "").unwrap();
+        let mut watcher = watcher(dir.path());
+        watcher.add_ignore("*.tmp").walk_async().await.unwrap();
+
+        watcher.save_async().await.unwrap();
+        let loaded: Watcher<N, N> = Watcher::load_async(dir.path().to_str().unwrap()).await.unwrap();
+        assert_eq!(loaded.dir_info, watcher.dir_info);
+        assert_eq!(loaded.ignore_list, watcher.ignore_list);
+    }
+
+    #[test]
+    fn errors_up_cast_to_dyn_error_and_expose_their_source() {
+        use std::error::Error;
+
+        let io = WatcherError::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        let source = (&io as &dyn Error).source().unwrap();
+        assert_eq!(source.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::PermissionDenied);
+
+        let node = WatcherError::from(FsNodeError::InvalidName);
+        let source = (&node as &dyn Error).source().unwrap();
+        assert!(matches!(source.downcast_ref::<FsNodeError>(), Some(FsNodeError::InvalidName)));
+        assert!((&FsNodeError::InvalidName as &dyn Error).source().is_none());
+
+        let boxed: Box<dyn Error> = Box::new(WatcherError::PathDoesNotExist);
+        assert!(boxed.source().is_none());
+        assert_eq!(boxed.to_string(), "Path does not exist");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn an_unreadable_dir_fails_the_walk_with_an_io_error() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Permission bits don't stop root
+        if unsafe { libc::geteuid() } == 0 { return; }
+        let dir = tempfile::tempdir().unwrap();
+        let locked = dir.path().join("locked");
+        std::fs::create_dir(&locked).unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+        let result = watcher(dir.path()).walk().map(|_| ());
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let Err(WatcherError::IOError(error)) = result else { panic!("{result:?}") };
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn streamed_nodes_match_a_full_walk_with_parents_first() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("b").join("c")).unwrap();
+        for file in ["a", "b/x", "b/c/y", "b/c/z"] {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+
+        let mut streamed = vec![];
+        let mut watcher = watcher(dir.path());
+        watcher.walk_streaming(|node| streamed.push(node.path())).unwrap();
+        for (i, path) in streamed.iter().enumerate() {
+            let parent = path.parent().unwrap();
+            assert!(parent == dir.path() || streamed[..i].iter().any(|p| p == parent), "{streamed:?}");
+        }
+
+        let mut walked = paths(watcher.walk().unwrap());
+        walked.sort();
+        let mut sorted = streamed.clone();
+        sorted.sort();
+        assert_eq!(sorted, walked);
+
+        let mut untouched = vec![];
+        watcher.stream(|node| untouched.push(node.path())).unwrap();
+        assert_eq!(untouched, streamed);
+    }
+
+    #[test]
+    fn progress_peaks_at_the_final_entry_count() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        for i in 0..PROGRESS_INTERVAL + 10 {
+            std::fs::write(dir.path().join("sub").join(i.to_string()), "").unwrap();
+        }
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let hook = Arc::clone(&seen);
+        let mut watcher = watcher(dir.path());
+        watcher.on_progress(move |count| hook.lock().unwrap().push(count)).walk().unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert!(seen.len() >= 2, "{seen:?}");
+        assert_eq!(seen.iter().max(), Some(&watcher.dir_info.entry_count()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walked_modes_match_what_was_set() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (locked, open) = (dir.path().join("locked"), dir.path().join("open"));
+        std::fs::write(&locked, "").unwrap();
+        std::fs::write(&open, "").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o444)).unwrap();
+        std::fs::set_permissions(&open, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let mut watcher = watcher(dir.path());
+        watcher.walk().unwrap();
+        let Some(FsNode::File(locked)) = watcher.dir_info.find(&locked) else { panic!() };
+        assert_eq!(locked.mode.map(|mode| mode & 0o777), Some(0o444));
+        assert!(locked.is_readonly());
+        let Some(FsNode::File(open)) = watcher.dir_info.find(&open) else { panic!() };
+        assert_eq!(open.mode.map(|mode| mode & 0o777), Some(0o640));
+        assert!(!open.is_readonly());
+        assert!(!watcher.dir_info.is_readonly());
+    }
+
+    #[test]
+    fn extension_filters_combine_and_still_descend_into_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("src.d").join("empty")).unwrap();
+        for file in ["lib.RS", "Cargo.toml", "notes.md", "src.d/main.rs", "src.d/gen.toml", "README"] {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+
+        let mut watcher = watcher(dir.path());
+        watcher.only_extensions(&[".rs", "TOML"]).exclude_extensions(&["toml"]).walk().unwrap();
+        let mut found = paths(&watcher);
+        found.sort();
+        assert_eq!(found, [
+            dir.path().join("lib.RS"),
+            dir.path().join("src.d"),
+            dir.path().join("src.d").join("empty"),
+            dir.path().join("src.d").join("main.rs"),
+        ]);
+    }
+
+    #[test]
+    fn rescan_replaces_one_subtree_and_leaves_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        std::fs::create_dir_all(b.join("inner")).unwrap();
+        std::fs::create_dir(&a).unwrap();
+        std::fs::write(a.join("kept"), "").unwrap();
+
+        let mut watcher: Watcher<String, u32> = Watcher::new(dir.path().to_str().unwrap()).unwrap();
+        watcher.walk().unwrap();
+        // Only survives if `a` isn't walked again
+        let Some(FsNode::File(kept)) = watcher.dir_info.find_mut(&a.join("kept")) else { panic!() };
+        kept.add_field(s!("mark"), 1);
+        let Some(FsNode::Directory(inner)) = watcher.dir_info.find_mut(&b.join("inner")) else { panic!() };
+        inner.set_expanded(false);
+        let before = watcher.dir_info.find(&a).cloned();
+
+        std::fs::write(dir.path().join("unseen"), "").unwrap();
+        std::fs::write(b.join("new"), "").unwrap();
+        watcher.rescan(&b).unwrap();
+
+        assert_eq!(watcher.dir_info.find(&a).cloned(), before);
+        assert!(watcher.dir_info.find(&b.join("new")).is_some());
+        assert!(watcher.dir_info.find(&dir.path().join("unseen")).is_none());
+        let Some(FsNode::Directory(inner)) = watcher.dir_info.find(&b.join("inner")) else { panic!() };
+        assert!(!inner.expanded);
+
+        let outside = tempfile::tempdir().unwrap();
+        assert!(matches!(watcher.rescan(outside.path()), Err(WatcherError::PathOutsideRoot)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn symlinks_are_walked_as_their_own_variant() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("real"), "").unwrap();
+        std::os::unix::fs::symlink("real", dir.path().join("relative")).unwrap();
+        std::os::unix::fs::symlink("/nowhere/at/all", dir.path().join("dangling")).unwrap();
+
+        let mut watcher = watcher(dir.path());
+        watcher.walk().unwrap();
+        let Some(FsNode::Symlink(link)) = watcher.dir_info.find(&dir.path().join("relative")) else { panic!() };
+        assert_eq!((link.name.as_str(), &link.target), ("relative", &dir.path().join("real")));
+        let Some(FsNode::Symlink(link)) = watcher.dir_info.find(&dir.path().join("dangling")) else { panic!() };
+        assert_eq!(link.target, Path::new("/nowhere/at/all"));
+
+        let lines = watcher.build_tree_plain();
+        let shown = format!("relative -> {}", dir.path().join("real").display());
+        assert!(lines.iter().any(|line| line.ends_with(&shown)), "{lines:?}");
+    }
+
+    #[cfg(any(feature = "content-hash", feature = "sha256"))]
+    #[test]
+    fn hashing_stores_the_digest_of_known_content() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("known"), "hello world").unwrap();
+
+        let mut watcher = watcher(dir.path());
+        watcher.with_hashing(true).walk().unwrap();
+        let Some(FsNode::File(file)) = watcher.dir_info.find(&dir.path().join("known")) else { panic!() };
+        #[cfg(not(feature = "sha256"))]
+        let expected = "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24";
+        #[cfg(feature = "sha256")]
+        let expected = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        assert_eq!(file.hash.as_deref(), Some(expected));
+
+        watcher.with_hashing(false).walk().unwrap();
+        let Some(FsNode::File(file)) = watcher.dir_info.find(&dir.path().join("known")) else { panic!() };
+        assert_eq!(file.hash, None);
+    }
+
+    #[test]
+    fn the_builder_sets_every_option() {
+        let dir = tempfile::tempdir().unwrap();
+        let watcher: Watcher<N, N> = WatcherBuilder::new()
+            .path(dir.path().to_str().unwrap())
+            .ignore_hidden(false)
+            .ignore("target")
+            .ignore("*.log")
+            .max_depth(2)
+            .follow_symlinks(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(watcher.path, dir.path());
+        assert!(!watcher.ignore_hidden);
+        assert_eq!(watcher.ignore_list, ["target", "*.log"]);
+        assert_eq!(watcher.max_depth, Some(2));
+        assert!(watcher.follow_symlinks);
+
+        let missing = WatcherBuilder::<N, N>::new().path(&s!(dir.path().join("missing").display())).build();
+        assert!(matches!(missing, Err(WatcherError::PathDoesNotExist)));
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml_round_trips_a_small_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub").join("file"), "x").unwrap();
+        let mut watcher = watcher(dir.path());
+        watcher.add_ignore("*.toml").walk().unwrap();
+
+        let file = dir.path().join("state.toml");
+        watcher.save_toml(&file).unwrap();
+        assert!(std::fs::read_to_string(&file).unwrap().starts_with("[watcher]"));
+        let loaded: Watcher<N, N> = Watcher::load_toml(&file).unwrap();
+        assert_eq!(loaded.dir_info, watcher.dir_info);
+        assert_eq!(loaded.ignore_list, watcher.ignore_list);
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml_round_trips_a_small_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), "x").unwrap();
+        let mut watcher: Watcher<String, u32> = Watcher::new(dir.path().to_str().unwrap()).unwrap();
+        watcher.walk().unwrap();
+        watcher.dir_info.add_field(s!("tag"), 5);
+
+        let file = dir.path().join("state.yaml");
+        watcher.save_yaml(&file).unwrap();
+        let loaded: Watcher<String, u32> = Watcher::load_yaml(&file).unwrap();
+        assert_eq!(loaded.dir_info, watcher.dir_info);
+    }
+
+    #[test]
+    fn path_ignores_hit_one_place_while_names_hit_everywhere() {
+        let dir = tempfile::tempdir().unwrap();
+        for generated in ["src/generated", "docs/generated"] {
+            std::fs::create_dir_all(dir.path().join(generated)).unwrap();
+            std::fs::write(dir.path().join(generated).join("out"), "").unwrap();
+        }
+        let kept = |watcher: &Watcher<N, N>, path: &str| watcher.dir_info.find(&dir.path().join(path)).is_some();
+
+        let mut watcher = watcher(dir.path());
+        watcher.add_ignore("src/generated").walk().unwrap();
+        assert!(!kept(&watcher, "src/generated"));
+        assert!(kept(&watcher, "docs/generated/out"));
+
+        watcher.ignore_reset().add_ignore("generated").walk().unwrap();
+        assert!(!kept(&watcher, "src/generated"));
+        assert!(!kept(&watcher, "docs/generated"));
+        assert!(kept(&watcher, "docs"));
+    }
+
+    #[test]
+    fn ignore_case_is_opt_in() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("thumbs.DB"), "").unwrap();
+
+        let mut watcher = watcher(dir.path());
+        watcher.add_ignore("Thumbs.db").walk().unwrap();
+        assert_eq!(paths(&watcher), [dir.path().join("thumbs.DB")]);
+
+        watcher.ignore_case_insensitive(true).walk().unwrap();
+        assert!(paths(&watcher).is_empty());
+    }
+
+    #[test]
+    fn regex_ignores_match_names_and_reject_bad_syntax() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["12.log", "a.log", "12.log.bak"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+
+        let mut watcher = watcher(dir.path());
+        watcher.add_ignore_regex(r"^\d+\.log$").unwrap().walk().unwrap();
+        let mut found = paths(&watcher);
+        found.sort();
+        assert_eq!(found, [dir.path().join("12.log.bak"), dir.path().join("a.log")]);
+
+        assert!(matches!(watcher.add_ignore_regex("(unclosed"), Err(WatcherError::InvalidPattern(_))));
+        assert_eq!(watcher.ignore_regexes.len(), 1);
+    }
+
+    #[test]
+    fn a_walk_past_the_node_budget_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..10 {
+            std::fs::write(dir.path().join(i.to_string()), "").unwrap();
+        }
+
+        let mut watcher = watcher(dir.path());
+        assert!(matches!(watcher.max_nodes(3).walk(), Err(WatcherError::NodeLimitExceeded)));
+        assert!(watcher.max_nodes(10).walk().is_ok());
+        assert_eq!(watcher.dir_info.entry_count(), 10);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_cycles_come_from_the_recorded_targets() {
+        use std::os::unix::fs::symlink;
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("sub").join("target")).unwrap();
+        std::fs::write(root.join("file"), "").unwrap();
+        symlink(root.join("self"), root.join("self")).unwrap();
+        symlink("..", root.join("sub").join("up")).unwrap();
+        symlink(root.join("b"), root.join("a")).unwrap();
+        symlink(root.join("a"), root.join("b")).unwrap();
+        // Neither leads back: a sibling directory and a file
+        symlink("sub/target", root.join("down")).unwrap();
+        symlink("file", root.join("to_file")).unwrap();
+
+        let mut watcher = watcher(root);
+        watcher.walk().unwrap();
+        let expected = ["a", "b", "self", "sub/up"].map(|name| root.join(name));
+        assert_eq!(watcher.find_symlink_cycles(), expected);
+
+        // Nothing is read from disk again
+        std::fs::remove_dir_all(root.join("sub")).unwrap();
+        for name in ["self", "a", "b", "down"] { std::fs::remove_file(root.join(name)).unwrap(); }
+        assert_eq!(watcher.find_symlink_cycles(), expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn streaming_skips_an_unreadable_dir_only_with_skip_errors() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Permission bits don't stop root
+        if unsafe { libc::geteuid() } == 0 { return; }
+        let dir = tempfile::tempdir().unwrap();
+        let locked = dir.path().join("locked");
+        std::fs::create_dir(&locked).unwrap();
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+        let mut watcher = watcher(dir.path());
+        let strict = watcher.walk_streaming(|_| {}).map(|_| ());
+        let mut streamed = vec![];
+        let lenient = watcher.skip_errors(true).walk_streaming(|node| streamed.push(node.path())).map(|_| ());
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let Err(WatcherError::IOError(error)) = strict else { panic!("{strict:?}") };
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        lenient.unwrap();
+        assert_eq!(streamed, [dir.path().join("file"), locked]);
+        assert_eq!(paths(&watcher), [dir.path().join("file")]);
+    }
+
+    #[test]
+    fn walk_entries_counts_toward_max_nodes_and_progress_in_name_order() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("m")).unwrap();
+        for file in ["z", "a", "m/y", "m/b"] { std::fs::write(dir.path().join(file), "").unwrap(); }
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let hook = Arc::clone(&seen);
+        let mut watcher = watcher(dir.path());
+        watcher.on_progress(move |count| hook.lock().unwrap().push(count));
+        let entries: Vec<_> = watcher.walk_entries().unwrap().into_iter()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(entries, ["a", "m", "m/b", "m/y", "z"].map(|name| dir.path().join(name)));
+        assert_eq!(*seen.lock().unwrap(), [5]);
+
+        assert!(matches!(watcher.max_nodes(4).walk_entries(), Err(WatcherError::NodeLimitExceeded)));
+        assert_eq!(watcher.max_nodes(5).walk_entries().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn configs_saved_without_max_depth_still_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut json = serde_json::to_value(watcher(dir.path())).unwrap();
+        json.as_object_mut().unwrap().remove("max_depth").unwrap();
+
+        let loaded: Watcher<N, N> = serde_json::from_value(json).unwrap();
+        assert_eq!(loaded.max_depth, None);
+    }
+
+    #[test]
+    fn configs_saved_without_respect_gitignore_still_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut json = serde_json::to_value(watcher(dir.path())).unwrap();
+        json.as_object_mut().unwrap().remove("respect_gitignore").unwrap();
+
+        let loaded: Watcher<N, N> = serde_json::from_value(json).unwrap();
+        assert!(!loaded.respect_gitignore);
+    }
+
+    #[test]
+    fn configs_saved_without_follow_symlinks_still_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut json = serde_json::to_value(watcher(dir.path())).unwrap();
+        json.as_object_mut().unwrap().remove("follow_symlinks").unwrap();
+
+        let loaded: Watcher<N, N> = serde_json::from_value(json).unwrap();
+        assert!(!loaded.follow_symlinks);
+    }
+
+    #[test]
+    fn long_paths_flags_entries_past_the_limit_in_characters() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        for file in ["ok", "éééé", "sub/deeper"] { std::fs::write(dir.path().join(file), "").unwrap(); }
+        let base = dir.path().to_string_lossy().chars().count();
+
+        let mut watcher = watcher(dir.path());
+        watcher.walk().unwrap();
+        assert!(watcher.long_paths().is_empty());
+
+        // "/éééé" is 5 characters but 9 bytes
+        watcher.max_path_len(base + 5).walk().unwrap();
+        assert_eq!(watcher.long_paths(), [dir.path().join("sub").join("deeper")]);
+
+        watcher.max_path_len(base + 3);
+        let mut long = watcher.long_paths();
+        long.sort();
+        assert_eq!(long, [dir.path().join("sub"), dir.path().join("sub").join("deeper"), dir.path().join("éééé")]);
+    }
+
+    #[cfg(feature = "git2")]
+    #[test]
+    fn git_status_annotations_follow_the_repo_until_the_next_walk() {
+        // Outside a repository nothing is annotated
+        let plain = tempfile::tempdir().unwrap();
+        std::fs::write(plain.path().join("file"), "").unwrap();
+        let mut outside = watcher(plain.path());
+        outside.walk().unwrap().annotate_git_status().unwrap();
+        assert!(matches!(outside.dir_info.content.as_slice(), [FsNode::File(f)] if f.git_status.is_none()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        for file in ["clean", "sub/changed"] { std::fs::write(dir.path().join(file), "v1").unwrap(); }
+
+        let mut index = repo.index().unwrap();
+        for file in ["clean", "sub/changed"] { index.add_path(Path::new(file)).unwrap(); }
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "initial", &tree, &[]).unwrap();
+
+        std::fs::write(dir.path().join("sub").join("changed"), "v2").unwrap();
+        std::fs::write(dir.path().join("staged"), "").unwrap();
+        index.add_path(Path::new("staged")).unwrap();
+        index.write().unwrap();
+        std::fs::write(dir.path().join("sub").join("new"), "").unwrap();
+
+        let mut watcher = watcher(dir.path());
+        watcher.walk().unwrap().annotate_git_status().unwrap();
+        let status = |watcher: &Watcher<N, N>, file: &str| match watcher.dir_info.find(&dir.path().join(file)) {
+            Some(FsNode::File(f)) => f.git_status,
+            _ => panic!("{file} not walked"),
+        };
+        assert_eq!(status(&watcher, "clean"), Some(GitStatus::Clean));
+        assert_eq!(status(&watcher, "sub/changed"), Some(GitStatus::Modified));
+        assert_eq!(status(&watcher, "staged"), Some(GitStatus::Added));
+        assert_eq!(status(&watcher, "sub/new"), Some(GitStatus::Untracked));
+
+        watcher.walk().unwrap();
+        for file in ["clean", "sub/changed", "staged", "sub/new"] { assert_eq!(status(&watcher, file), None); }
+    }
+}
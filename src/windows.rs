@@ -0,0 +1,265 @@
+use crate::{backend::*, inotify::Event};
+use std::{
+    collections::HashMap, ffi::c_void, io, os::windows::ffi::OsStrExt, path::{Path, PathBuf},
+    time::SystemTime,
+};
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, ERROR_IO_INCOMPLETE, FALSE, HANDLE, INVALID_HANDLE_VALUE, TRUE},
+    Storage::FileSystem::{
+        CreateFileW, ReadDirectoryChangesW, FILE_ACTION_ADDED, FILE_ACTION_MODIFIED,
+        FILE_ACTION_REMOVED, FILE_ACTION_RENAMED_NEW_NAME, FILE_ACTION_RENAMED_OLD_NAME,
+        FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_OVERLAPPED, FILE_LIST_DIRECTORY,
+        FILE_NOTIFY_CHANGE_DIR_NAME, FILE_NOTIFY_CHANGE_FILE_NAME, FILE_NOTIFY_CHANGE_LAST_WRITE,
+        FILE_NOTIFY_CHANGE_SIZE, FILE_NOTIFY_INFORMATION, FILE_SHARE_DELETE, FILE_SHARE_READ,
+        FILE_SHARE_WRITE, OPEN_EXISTING,
+    },
+    System::IO::{CancelIoEx, GetOverlappedResult, OVERLAPPED},
+};
+
+/// Bytes of notifications each watch buffers between reads
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// ReadDirectoryChangesW backed watching on Windows.
+///
+/// Windows reports entries added, removed, modified and renamed, which
+/// become `Create`, `Delete`, `Modify` and `Renamed`; a rename whose other
+/// half falls in another read is reported as `MovedFrom` or `MovedTo`.
+/// Names are relative to the watched directory. Only directories can be
+/// watched, and changes that overflow the buffer are reported as a single
+/// `Overflow` with an empty path.
+pub struct WindowsBackend {
+    watches: HashMap<WatchId, Box<DirWatch>>,
+    next_id: WatchId,
+    recursive: bool,
+}
+
+impl WindowsBackend {
+    pub fn new() -> Result<Self, BackendError> {
+        return Ok(Self { watches: HashMap::new(), next_id: 1, recursive: false });
+    }
+
+    /// Also reports changes anywhere beneath a watched directory. Applies to
+    /// later watch() calls.
+    pub fn recursive(&mut self, enabled: bool) -> &mut Self {
+        self.recursive = enabled;
+        return self;
+    }
+}
+
+impl FileWatchBackend for WindowsBackend {
+    fn watch(&mut self, path: &Path, events: &[Event]) -> Result<WatchId, BackendError> {
+        let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+        let handle = unsafe {
+            CreateFileW(
+                wide.as_ptr(),
+                FILE_LIST_DIRECTORY,
+                FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+                std::ptr::null(),
+                OPEN_EXISTING,
+                FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OVERLAPPED,
+                0)};
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(BackendError::OSError(io::Error::last_os_error()));
+        }
+
+        let mut watch = Box::new(DirWatch {
+            handle,
+            path: path.to_path_buf(),
+            filter: notify_filter(events),
+            recursive: self.recursive,
+            buffer: vec![0; BUFFER_SIZE / 4],
+            overlapped: unsafe { std::mem::zeroed() },
+        });
+        watch.start()?;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watches.insert(id, watch);
+        return Ok(id);
+    }
+
+    fn unwatch(&mut self, id: WatchId) -> Result<(), BackendError> {
+        return match self.watches.remove(&id) {
+            Some(_) => Ok(()),
+            None => Err(BackendError::OSError(io::Error::from(io::ErrorKind::NotFound))),
+        };
+    }
+
+    fn read_events(&mut self) -> Result<Vec<FsEvent>, BackendError> {
+        let mut events = vec![];
+        for (id, watch) in self.watches.iter_mut() {
+            events.extend(watch.take(*id)?);
+        }
+        return Ok(events);
+    }
+}
+
+/// One watched directory and its outstanding read. Boxed, as Windows writes
+/// into `buffer` and `overlapped` until the read completes.
+struct DirWatch {
+    handle: HANDLE,
+    path: PathBuf,
+    filter: u32,
+    recursive: bool,
+    // u32s keep the records DWORD aligned, as Windows requires
+    buffer: Vec<u32>,
+    overlapped: OVERLAPPED,
+}
+
+impl DirWatch {
+    /// Queues the next read, collected by take()
+    fn start(&mut self) -> Result<(), BackendError> {
+        self.overlapped = unsafe { std::mem::zeroed() };
+        let started = unsafe {
+            ReadDirectoryChangesW(
+                self.handle,
+                self.buffer.as_mut_ptr() as *mut c_void,
+                (self.buffer.len() * 4) as u32,
+                if self.recursive { TRUE } else { FALSE },
+                self.filter,
+                std::ptr::null_mut(),
+                &mut self.overlapped,
+                None)};
+        if started == FALSE {
+            return Err(BackendError::OSError(io::Error::last_os_error()));
+        }
+        return Ok(());
+    }
+
+    /// The events of a finished read, after queueing the next one, or none
+    /// while it is still pending
+    fn take(&mut self, id: WatchId) -> Result<Vec<FsEvent>, BackendError> {
+        let mut bytes = 0;
+        let done = unsafe { GetOverlappedResult(self.handle, &self.overlapped, &mut bytes, FALSE) };
+        if done == FALSE {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() == Some(ERROR_IO_INCOMPLETE as i32) { return Ok(vec![]); }
+            return Err(BackendError::OSError(error));
+        }
+
+        let events = match bytes {
+            // More changed than the buffer could hold
+            0 => vec![FsEvent {
+                wd: id,
+                event: Event::Overflow,
+                name: String::new(),
+                path: PathBuf::new(),
+                from: None,
+                time: SystemTime::now(),
+            }],
+            _ => self.parse(id, bytes as usize),
+        };
+        self.start()?;
+        return Ok(events);
+    }
+
+    /// Reads the FILE_NOTIFY_INFORMATION records in the first `len` bytes
+    /// of the buffer
+    fn parse(&self, id: WatchId, len: usize) -> Vec<FsEvent> {
+        let time = SystemTime::now();
+        let header = std::mem::size_of::<FILE_NOTIFY_INFORMATION>();
+        let bytes = self.buffer.as_ptr() as *const u8;
+        let mut events: Vec<FsEvent> = vec![];
+        let mut offset = 0;
+
+        while offset + header <= len {
+            let info = unsafe { bytes.add(offset) as *const FILE_NOTIFY_INFORMATION };
+            let (action, next, name_len) = unsafe {
+                ((*info).Action, (*info).NextEntryOffset as usize, (*info).FileNameLength as usize)
+            };
+            // The name runs on past the one u16 the struct declares
+            let name = unsafe {
+                let start = std::ptr::addr_of!((*info).FileName) as *const u16;
+                std::slice::from_raw_parts(start, name_len / 2)
+            };
+            let name = String::from_utf16_lossy(name);
+            let event = FsEvent {
+                wd: id,
+                event: Event::Uknown,
+                path: self.path.join(&name),
+                name,
+                from: None,
+                time,
+            };
+
+            match action {
+                FILE_ACTION_ADDED => events.push(FsEvent { event: Event::Create, ..event }),
+                FILE_ACTION_REMOVED => events.push(FsEvent { event: Event::Delete, ..event }),
+                FILE_ACTION_MODIFIED => events.push(FsEvent { event: Event::Modify, ..event }),
+                FILE_ACTION_RENAMED_OLD_NAME => {
+                    events.push(FsEvent { event: Event::MovedFrom, ..event });
+                },
+                // Follows its old name directly when both are in this read
+                FILE_ACTION_RENAMED_NEW_NAME => {
+                    match events.pop_if(|last| matches!(last.event, Event::MovedFrom)) {
+                        Some(old) => events.push(FsEvent {
+                            event: Event::Renamed, from: Some(old.path), ..event
+                        }),
+                        None => events.push(FsEvent { event: Event::MovedTo, ..event }),
+                    }
+                },
+                _ => {},
+            }
+
+            if next == 0 { break; }
+            offset += next;
+        }
+        return events;
+    }
+}
+
+impl Drop for DirWatch {
+    fn drop(&mut self) {
+        unsafe {
+            // The read has to end before its buffer is freed
+            if CancelIoEx(self.handle, &self.overlapped) != FALSE {
+                let mut bytes = 0;
+                GetOverlappedResult(self.handle, &self.overlapped, &mut bytes, TRUE);
+            }
+            CloseHandle(self.handle);
+        }
+    }
+}
+
+/// The kinds of change to ask Windows for, all of them when `events` is
+/// empty
+fn notify_filter(events: &[Event]) -> u32 {
+    let names = FILE_NOTIFY_CHANGE_FILE_NAME | FILE_NOTIFY_CHANGE_DIR_NAME;
+    let writes = FILE_NOTIFY_CHANGE_LAST_WRITE | FILE_NOTIFY_CHANGE_SIZE;
+    if events.is_empty() { return names | writes; }
+
+    return events.iter().fold(0, |filter, event| filter | match event {
+        Event::Create | Event::Delete | Event::MovedFrom | Event::MovedTo | Event::Renamed => names,
+        Event::Modify | Event::CloseWrite | Event::Attrib => writes,
+        _ => 0,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn a_new_file_is_created_under_its_relative_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut backend = WindowsBackend::new().unwrap();
+        backend.recursive(true).watch(dir.path(), &[]).unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub").join("new.txt"), "").unwrap();
+
+        // The read completes in the background, so poll until it is in
+        let mut events = vec![];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !events.iter().any(|e: &FsEvent| e.name.ends_with("new.txt")) && Instant::now() < deadline {
+            events.extend(backend.read_events().unwrap());
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        let created = events.iter()
+            .find(|event| matches!(event.event, Event::Create) && event.name.ends_with("new.txt"))
+            .unwrap();
+        assert_eq!(created.name, "sub\\new.txt");
+        assert_eq!(created.path, dir.path().join("sub").join("new.txt"));
+    }
+}